@@ -0,0 +1,8 @@
+#![no_main]
+use chem_storage::SerCompound;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|cmp: SerCompound| {
+    // Parsing must reject bad input with an error, never a panic.
+    let _ = cmp.to_compound();
+});
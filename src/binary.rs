@@ -0,0 +1,81 @@
+//! The cache layout encoded with bincode, for compact local snapshots. bincode needs every field present and can't `flatten`, so the JSON model is mirrored here without those attributes.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{CacheError, CompoundCache, DeserializeOptions, SerAlias, SerCache, SerCompound, SerEntry, SerProperties, CACHE_FORMAT_VERSION};
+
+#[derive(Serialize, Deserialize)]
+struct BinCache {
+    version: u32,
+    generated_at: Option<String>,
+    cache: Vec<BinEntry>,
+    /// `(alias, primary)` pairs.
+    aliases: Vec<(SerCompound, SerCompound)>
+}
+
+#[derive(Serialize, Deserialize)]
+struct BinEntry {
+    key: SerCompound,
+    properties: SerProperties,
+    annotations: BTreeMap<String, String>,
+    image_png: Option<String>,
+    source: Option<SerCompound>,
+    access_count: u64,
+    fetched_at: Option<u64>,
+    fetched: Option<Vec<String>>
+}
+impl From<SerEntry> for BinEntry {
+    fn from(entry: SerEntry) -> Self {
+        BinEntry {
+            key: entry.key,
+            properties: entry.properties,
+            annotations: entry.annotations,
+            image_png: entry.image_png,
+            source: entry.source,
+            access_count: entry.access_count,
+            fetched_at: entry.fetched_at,
+            fetched: entry.fetched
+        }
+    }
+}
+impl From<BinEntry> for SerEntry {
+    fn from(entry: BinEntry) -> Self {
+        SerEntry {
+            key: entry.key,
+            properties: entry.properties,
+            annotations: entry.annotations,
+            image_png: entry.image_png,
+            source: entry.source,
+            access_count: entry.access_count,
+            fetched_at: entry.fetched_at,
+            fetched: entry.fetched
+        }
+    }
+}
+
+pub(crate) fn encode(cache: &CompoundCache) -> Result<Vec<u8>, CacheError> {
+    let file = SerCache::new(&cache.cache);
+    let file = BinCache {
+        version: file.version,
+        generated_at: file.generated_at,
+        cache: file.cache.into_iter().map(BinEntry::from).collect(),
+        aliases: cache.aliases.iter().map(|(alias, primary)| (alias.clone(), primary.clone())).collect()
+    };
+    bincode::serialize(&file).map_err(|e| CacheError::Format(format!("Could not encode bincode! ({e})")))
+}
+
+pub(crate) fn decode(bytes: &[u8]) -> Result<CompoundCache, CacheError> {
+    let file: BinCache = bincode::deserialize(bytes).map_err(|e| CacheError::Format(format!("Could not decode bincode! ({e})")))?;
+    if file.version > CACHE_FORMAT_VERSION {
+        return Err(CacheError::UnsupportedVersion(file.version));
+    }
+    let file = SerCache {
+        version: file.version,
+        generated_at: file.generated_at,
+        cache: file.cache.into_iter().map(SerEntry::from).collect(),
+        aliases: file.aliases.into_iter().map(|(alias, primary)| SerAlias { alias, primary }).collect()
+    };
+    file.into_cache(&DeserializeOptions::default())
+}
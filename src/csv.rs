@@ -0,0 +1,57 @@
+//! Minimal CSV handling for identifier lists. Fields may be double-quoted, with `""` as an escaped quote.
+
+use std::borrow::Cow;
+
+use crate::{CacheError, SerCompound};
+
+/// Splits one CSV line into its fields.
+pub(crate) fn parse_row(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c)
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Parses line `i` (counted from 0) of a `namespace,identifier` list. `None` for blank lines and a header on the first line.
+pub(crate) fn parse_identifier_row(i: usize, line: &str) -> Result<Option<SerCompound>, CacheError> {
+    if line.trim().is_empty() {
+        return Ok(None);
+    }
+    let mut fields = parse_row(line).into_iter();
+    let (namespace, identifier) = (fields.next().unwrap_or_default(), fields.next().unwrap_or_default());
+    if i == 0 && namespace.trim().eq_ignore_ascii_case("namespace") && identifier.trim().eq_ignore_ascii_case("identifier") {
+        return Ok(None);
+    }
+    if fields.next().is_some() {
+        return Err(CacheError::Format(format!("Row {} has more than two fields!", i + 1)));
+    }
+    SerCompound::try_with_nmsp_iden(&namespace, &identifier)
+        .map(Some)
+        .map_err(|e| CacheError::Format(format!("Row {} could not be parsed: {e}", i + 1)))
+}
+
+/// Formats one field, quoting it if it contains a comma, a quote, or a line break.
+pub(crate) fn quote_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
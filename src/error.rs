@@ -0,0 +1,127 @@
+use std::{error::Error, fmt::{self, Display}, io, iter};
+
+use crate::SerCompound;
+
+/// Errors produced by `CompoundCache` operations.
+#[derive(Debug)]
+pub enum CacheError {
+    /// Reading or writing a cache file failed.
+    Io(io::Error),
+    /// The cache file was not valid JSON.
+    Json(serde_json::Error),
+    /// The JSON was valid, but not laid out like a cache.
+    Format(String),
+    /// The cache file was written by a newer (or unknown) format version.
+    UnsupportedVersion(u32),
+    /// The fetch budget was spent, so no request was made.
+    BudgetExceeded,
+    /// The compound had to be cached already, but wasn't.
+    NotCached(SerCompound),
+    /// A property needed by the operation was `None`.
+    MissingField(&'static str),
+    /// A namespace name did not match any `Namespace`.
+    UnknownNamespace(String),
+    /// The identifier of a `cid` compound is not a valid CID.
+    InvalidCid(String),
+    /// The PubChem request failed.
+    Network(pubchem::error::Error),
+    /// No cache format is known for the file.
+    UnsupportedFormat(String),
+    /// The fetcher can't provide this kind of data.
+    Unsupported(&'static str),
+    /// None of several identifiers could be resolved. Holds each identifier's error, in the order they were tried.
+    AllFailed(Vec<(SerCompound, CacheError)>),
+    /// The compound's fetch failed before and negative caching is on, so it wasn't fetched again. Holds the original error message.
+    PreviouslyFailed(String),
+    /// The identifier is empty or contains control characters.
+    InvalidIdentifier(String),
+    /// PubChem returned fewer properties than the cache's minimum, with strict checking on. Holds how many were populated and how many were requested.
+    DegradedResponse(usize, usize),
+    /// A structure file had no InChI or SMILES to look the compound up by. Holds the file's path.
+    NoIdentifier(String),
+    /// The identifier of a `smiles` compound is not well-formed SMILES (e.g. unknown atoms or unbalanced parentheses).
+    InvalidSmiles(String),
+    /// The identifier of an `inchikey` compound doesn't have the `XXXXXXXXXXXXXX-XXXXXXXXXX-X` shape of an InChIKey.
+    InvalidInchiKey(String),
+    /// A `PropertyFetcher` got an HTTP error status back. Holds the status code.
+    Status(u16)
+}
+
+impl Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheError::Io(e) => write!(f, "Could not access the cache file! ({e})"),
+            CacheError::Json(e) => write!(f, "Could not parse JSON! ({e})"),
+            CacheError::Format(e) => write!(f, "Malformed cache! ({e})"),
+            CacheError::UnsupportedVersion(v) => write!(f, "Unsupported cache format version {v}!"),
+            CacheError::BudgetExceeded => write!(f, "The fetch budget has been exhausted!"),
+            CacheError::NotCached(cmp) => write!(f, "{cmp:?} is not cached!"),
+            CacheError::MissingField(field) => write!(f, "`{field}` is missing!"),
+            CacheError::UnknownNamespace(ns) => write!(f, "Unknown namespace `{ns}`!"),
+            CacheError::InvalidCid(cid) => write!(f, "`{cid}` is not a valid CID!"),
+            CacheError::Network(e) => write!(f, "PubChem request failed! ({e})"),
+            CacheError::UnsupportedFormat(path) => write!(f, "No cache format is known for `{path}`!"),
+            CacheError::Unsupported(what) => write!(f, "{what} are not supported by this fetcher!"),
+            CacheError::AllFailed(errors) => {
+                write!(f, "None of the {} identifiers could be resolved!", errors.len())?;
+                for (cmp, e) in errors {
+                    write!(f, " ({}:{}: {e})", cmp.namespace, cmp.identifier)?;
+                }
+                Ok(())
+            }
+            CacheError::PreviouslyFailed(e) => write!(f, "An earlier fetch of this compound failed, so it was not retried! ({e})"),
+            CacheError::InvalidIdentifier(id) => write!(f, "{id:?} is not a valid identifier!"),
+            CacheError::DegradedResponse(populated, requested) => write!(f, "Only {populated} of {requested} requested properties were returned!"),
+            CacheError::NoIdentifier(path) => write!(f, "`{path}` has no InChI or SMILES data item!"),
+            CacheError::InvalidSmiles(smiles) => write!(f, "`{smiles}` is not valid SMILES!"),
+            CacheError::InvalidInchiKey(key) => write!(f, "`{key}` is not a valid InChIKey!"),
+            CacheError::Status(status) => write!(f, "The request failed with HTTP status {status}!")
+        }
+    }
+}
+
+impl CacheError {
+    /// Whether trying again later might succeed: timeouts and dropped connections, rate limiting (HTTP 429) and server errors (HTTP 5xx). `PubChemFetcher` reports PubChem's server faults as `Status`; other PubChem errors count only when an I/O error caused them. Anything else about the request, like an identifier PubChem doesn't know, is not transient.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            CacheError::Io(e) => is_transient_io(e),
+            CacheError::Network(e) => iter::successors(Some(e as &(dyn Error + 'static)), |&e| e.source())
+                .any(|e| e.downcast_ref::<io::Error>().is_some_and(is_transient_io)),
+            CacheError::Status(status) => *status == 429 || (500..600).contains(status),
+            _ => false
+        }
+    }
+}
+
+fn is_transient_io(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::Interrupted | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted)
+}
+
+impl Error for CacheError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CacheError::Io(e) => Some(e),
+            CacheError::Json(e) => Some(e),
+            CacheError::Network(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for CacheError {
+    fn from(e: io::Error) -> Self {
+        CacheError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for CacheError {
+    fn from(e: serde_json::Error) -> Self {
+        CacheError::Json(e)
+    }
+}
+
+impl From<pubchem::error::Error> for CacheError {
+    fn from(e: pubchem::error::Error) -> Self {
+        CacheError::Network(e)
+    }
+}
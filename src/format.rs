@@ -0,0 +1,119 @@
+//! Pluggable on-disk formats for `CompoundCache`, picked by file extension.
+
+use std::{fs, path::Path};
+
+use serde_json::Value;
+
+use crate::{write_locked, CacheError, CompoundCache, DeserializeOptions, CACHE_FORMAT_VERSION};
+
+/// A way of turning a `CompoundCache` into bytes and back.
+pub trait CacheFormat {
+    /// File extensions (without the dot) this format is used for.
+    fn extensions(&self) -> &'static [&'static str];
+    fn serialize(&self, cache: &CompoundCache) -> Result<Vec<u8>, CacheError>;
+    fn deserialize(&self, bytes: &[u8]) -> Result<CompoundCache, CacheError>;
+}
+
+/// The JSON layout written by `CompoundCache::serialize`. Older format versions are migrated on read.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct JsonFormat;
+impl CacheFormat for JsonFormat {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["json"]
+    }
+    fn serialize(&self, cache: &CompoundCache) -> Result<Vec<u8>, CacheError> {
+        Ok(cache.serialize()?.to_string().into_bytes())
+    }
+    fn deserialize(&self, bytes: &[u8]) -> Result<CompoundCache, CacheError> {
+        let mut root = serde_json::from_slice::<Value>(bytes)?;
+        let version = crate::format_version(&root);
+        if version < CACHE_FORMAT_VERSION {
+            root = crate::migrate(root, version)?;
+        }
+        CompoundCache::from_value(&root, &DeserializeOptions::default())
+    }
+}
+
+/// The JSON layout encoded as MessagePack, for compact snapshots read by other languages. Requires the `rmp` feature.
+#[cfg(feature = "rmp")]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct MsgPackFormat;
+#[cfg(feature = "rmp")]
+impl CacheFormat for MsgPackFormat {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["msgpack"]
+    }
+    fn serialize(&self, cache: &CompoundCache) -> Result<Vec<u8>, CacheError> {
+        let root = cache.serialize()?;
+        rmp_serde::to_vec(&root).map_err(|e| CacheError::Format(format!("Could not encode MessagePack! ({e})")))
+    }
+    fn deserialize(&self, bytes: &[u8]) -> Result<CompoundCache, CacheError> {
+        let mut root = rmp_serde::from_slice::<Value>(bytes).map_err(|e| CacheError::Format(format!("Could not decode MessagePack! ({e})")))?;
+        let version = crate::format_version(&root);
+        if version < CACHE_FORMAT_VERSION {
+            root = crate::migrate(root, version)?;
+        }
+        CompoundCache::from_value(&root, &DeserializeOptions::default())
+    }
+}
+
+/// The cache encoded with bincode, for compact snapshots only this crate reads. Requires the `bincode` feature.
+#[cfg(feature = "bincode")]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct BincodeFormat;
+#[cfg(feature = "bincode")]
+impl CacheFormat for BincodeFormat {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["bin"]
+    }
+    fn serialize(&self, cache: &CompoundCache) -> Result<Vec<u8>, CacheError> {
+        cache.serialize_binary()
+    }
+    fn deserialize(&self, bytes: &[u8]) -> Result<CompoundCache, CacheError> {
+        CompoundCache::deserialize_binary(bytes)
+    }
+}
+
+/// Every built-in format.
+pub fn formats() -> Vec<Box<dyn CacheFormat>> {
+    vec![
+        Box::new(JsonFormat),
+        #[cfg(feature = "rmp")]
+        Box::new(MsgPackFormat),
+        #[cfg(feature = "bincode")]
+        Box::new(BincodeFormat)
+    ]
+}
+
+/// The built-in format whose extensions include `path`'s extension.
+pub fn format_for_path(path: &Path) -> Option<Box<dyn CacheFormat>> {
+    let extension = path.extension()?.to_str()?;
+    formats().into_iter().find(|format| format.extensions().iter().any(|ext| ext.eq_ignore_ascii_case(extension)))
+}
+
+fn detect(path: &Path) -> Result<Box<dyn CacheFormat>, CacheError> {
+    format_for_path(path).ok_or_else(|| CacheError::UnsupportedFormat(path.display().to_string()))
+}
+
+/// Writes `cache` to `path` in `format`, or in the format matching the path's extension if `None`. Like `CompoundCache::save_to_path`, the file is replaced atomically, under the exclusive lock with the `fs-lock` feature.
+pub fn save<P: AsRef<Path>>(cache: &CompoundCache, path: P, format: Option<&dyn CacheFormat>) -> Result<(), CacheError> {
+    let path = path.as_ref();
+    let bytes = match format {
+        Some(format) => format.serialize(cache)?,
+        None => detect(path)?.serialize(cache)?
+    };
+    write_locked(path, &bytes)?;
+    // With an explicit format, the file may not be what `save_to_path` would write there
+    cache.mark_saved(format.is_none().then_some(path));
+    Ok(())
+}
+
+/// Reads a cache from `path` in `format`, or in the format matching the path's extension if `None`.
+pub fn load<P: AsRef<Path>>(path: P, format: Option<&dyn CacheFormat>) -> Result<CompoundCache, CacheError> {
+    let path = path.as_ref();
+    let bytes = fs::read(path)?;
+    match format {
+        Some(format) => format.deserialize(&bytes),
+        None => detect(path)?.deserialize(&bytes)
+    }
+}
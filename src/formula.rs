@@ -0,0 +1,83 @@
+use pubchem::model::rest::Properties;
+
+/// Parses a molecular formula like `C9H8O4` into `(element, count)` pairs in order of first appearance, merging repeated elements.
+///
+/// A trailing charge (`H+`, `Cl-`, `O4S-2`) is ignored. Returns `None` if the formula is not in that shape.
+pub fn parse_formula(formula: &str) -> Option<Vec<(String, u32)>> {
+    let mut counts: Vec<(String, u32)> = Vec::new();
+    let mut chars = formula.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            'A'..='Z' => {
+                let mut symbol = c.to_string();
+                while let Some(&lower @ 'a'..='z') = chars.peek() {
+                    symbol.push(lower);
+                    chars.next();
+                }
+                let mut digits = String::new();
+                while let Some(&digit @ '0'..='9') = chars.peek() {
+                    digits.push(digit);
+                    chars.next();
+                }
+                let count = if digits.is_empty() { 1 } else { digits.parse().ok()? };
+                match counts.iter_mut().find(|(element, _)| *element == symbol) {
+                    Some((_, total)) => *total += count,
+                    None => counts.push((symbol, count))
+                }
+            }
+            '+' | '-' => {
+                // The charge ends the formula, only its magnitude may follow
+                if !chars.all(|c| c.is_ascii_digit()) {
+                    return None;
+                }
+            }
+            _ => return None
+        }
+    }
+    if counts.is_empty() {
+        None
+    } else {
+        Some(counts)
+    }
+}
+
+/// Element counts of the compound's `molecular_formula`. Works for compounds without heavy atoms (e.g. `H+`).
+pub fn element_counts(props: &Properties) -> Option<Vec<(String, u32)>> {
+    parse_formula(props.molecular_formula.as_ref()?)
+}
+
+/// Whether the compound is a single atom or monatomic ion (`Na+`, `H+`), judged by its formula.
+pub fn is_single_atom(props: &Properties) -> bool {
+    match element_counts(props) {
+        Some(counts) => counts.iter().map(|(_, count)| count).sum::<u32>() == 1,
+        None => false
+    }
+}
+
+/// Rewrites a formula in Hill notation: carbon, then hydrogen, then the other elements alphabetically. Without carbon, every element is alphabetical. A trailing charge is kept as-is.
+pub fn hill_formula(formula: &str) -> Option<String> {
+    let mut counts = parse_formula(formula)?;
+    let has_carbon = counts.iter().any(|(element, _)| element == "C");
+    counts.sort_by_key(|(element, _)| match element.as_str() {
+        "C" if has_carbon => (0, String::new()),
+        "H" if has_carbon => (1, String::new()),
+        _ => (2, element.clone())
+    });
+    let mut hill = String::new();
+    for (element, count) in counts {
+        hill.push_str(&element);
+        if count != 1 {
+            hill.push_str(&count.to_string());
+        }
+    }
+    let formula = formula.trim();
+    if let Some(charge) = formula.find(['+', '-']) {
+        hill.push_str(&formula[charge..]);
+    }
+    Some(hill)
+}
+
+/// The compound's `molecular_formula` in Hill notation (see `hill_formula`).
+pub fn molecular_formula_hill(props: &Properties) -> Option<String> {
+    hill_formula(props.molecular_formula.as_ref()?)
+}
@@ -1,10 +1,44 @@
 #![allow(dead_code)]
-use std::{collections::HashMap, hash::Hash, error::Error};
+use std::{borrow::Cow, io::{self, BufRead, BufReader, Read, Write}, collections::{BTreeMap, HashMap, HashSet, hash_map::DefaultHasher}, fmt, hash::{Hash, Hasher}, fs, path::{Path, PathBuf}, str::FromStr, thread, time::{Duration, Instant, SystemTime, UNIX_EPOCH}, sync::{Arc, Mutex, PoisonError, atomic::{AtomicBool, AtomicU64, Ordering}}};
 
 use pubchem::{Compound, model::rest::Properties, CompoundProperty};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
 use serde_json::{Value, Map};
 
-const ALL_PROPERTIES: &[CompoundProperty] = &[
+use rate::RateLimiter;
+
+mod base64;
+#[cfg(feature = "bincode")]
+mod binary;
+mod csv;
+mod error;
+mod rate;
+mod sdf;
+mod shared;
+mod smiles;
+mod timestamp;
+pub mod format;
+pub use format::{CacheFormat, JsonFormat};
+#[cfg(feature = "rmp")]
+pub use format::MsgPackFormat;
+#[cfg(feature = "bincode")]
+pub use format::BincodeFormat;
+pub use error::CacheError;
+pub use shared::{SharedCompoundCache, global, global_load_error, GLOBAL_CACHE_PATH_VAR};
+pub mod formula;
+pub use formula::{parse_formula, element_counts, is_single_atom, hill_formula, molecular_formula_hill};
+
+/// Version of the on-disk cache layout written by `serialize`. Files without a `version` field are version 0.
+pub const CACHE_FORMAT_VERSION: u32 = 1;
+
+/// Bytes `estimated_json_bytes` assumes for each numeric property.
+const ESTIMATED_NUMBER_BYTES: usize = 6;
+
+/// How many CID rows `CompoundCache::cache_from_csv_reader` fetches per request.
+pub const CSV_CID_BATCH: usize = 100;
+
+/// Every property PubChem can report. This is what gets fetched unless a namespace is configured otherwise.
+pub const ALL_PROPERTIES: &[CompoundProperty] = &[
     // big property
     CompoundProperty::MolecularFormula,
     CompoundProperty::MolecularWeight,
@@ -49,260 +83,2875 @@ const ALL_PROPERTIES: &[CompoundProperty] = &[
     CompoundProperty::Fingerprint2D
 ];
 
-#[derive(Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Debug)]
+/// Every property except the 3D descriptors.
+pub const PROPERTIES_2D: &[CompoundProperty] = &[
+    CompoundProperty::MolecularFormula,
+    CompoundProperty::MolecularWeight,
+    CompoundProperty::CanonicalSMILES,
+    CompoundProperty::IsomericSMILES,
+    CompoundProperty::InChI,
+    CompoundProperty::InChIKey,
+    CompoundProperty::IUPACName,
+    CompoundProperty::Title,
+    CompoundProperty::XLogP,
+    CompoundProperty::ExactMass,
+    CompoundProperty::MonoisotopicMass,
+    CompoundProperty::TPSA,
+    CompoundProperty::Complexity,
+    CompoundProperty::Charge,
+    CompoundProperty::HBondDonorCount,
+    CompoundProperty::HBondAcceptorCount,
+    CompoundProperty::RotatableBondCount,
+    CompoundProperty::HeavyAtomCount,
+    CompoundProperty::IsotopeAtomCount,
+    CompoundProperty::AtomStereoCount,
+    CompoundProperty::DefinedAtomStereoCount,
+    CompoundProperty::UndefinedAtomStereoCount,
+    CompoundProperty::BondStereoCount,
+    CompoundProperty::DefinedBondStereoCount,
+    CompoundProperty::UndefinedBondStereoCount,
+    CompoundProperty::CovalentUnitCount,
+    CompoundProperty::Fingerprint2D
+];
+
+/// Identity-only properties, for lookups where descriptors aren't needed (e.g. name searches).
+pub const IDENTITY_PROPERTIES: &[CompoundProperty] = &[
+    CompoundProperty::MolecularFormula,
+    CompoundProperty::MolecularWeight,
+    CompoundProperty::CanonicalSMILES,
+    CompoundProperty::IsomericSMILES,
+    CompoundProperty::InChI,
+    CompoundProperty::InChIKey,
+    CompoundProperty::IUPACName,
+    CompoundProperty::Title
+];
+
+/// A PubChem input namespace, i.e. what kind of identifier a `SerCompound` holds. Written to cache files as its lowercase name.
+#[derive(Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Namespace {
+    Cid,
+    Name,
+    Smiles,
+    InChI,
+    InChIKey
+}
+impl Namespace {
+    pub const ALL: &'static [Namespace] = &[Namespace::Cid, Namespace::Name, Namespace::Smiles, Namespace::InChI, Namespace::InChIKey];
+    /// The name PubChem and cache files use, e.g. `"inchikey"`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Namespace::Cid => "cid",
+            Namespace::Name => "name",
+            Namespace::Smiles => "smiles",
+            Namespace::InChI => "inchi",
+            Namespace::InChIKey => "inchikey"
+        }
+    }
+}
+impl FromStr for Namespace {
+    type Err = CacheError;
+    /// Parses a namespace name, ignoring ASCII case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Namespace::ALL.iter()
+            .find(|ns| ns.as_str().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| CacheError::UnknownNamespace(s.to_owned()))
+    }
+}
+impl fmt::Display for Namespace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Whether an InChI is standard (`InChI=1S/...`), with PubChem's fixed layers, or non-standard (`InChI=1/...`).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InchiKind {
+    Standard,
+    NonStandard
+}
+impl InchiKind {
+    /// `None` if `inchi` has neither prefix.
+    pub fn of(inchi: &str) -> Option<InchiKind> {
+        if inchi.starts_with("InChI=1S/") {
+            Some(InchiKind::Standard)
+        } else if inchi.starts_with("InChI=1/") {
+            Some(InchiKind::NonStandard)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether `key` has the shape of an InChIKey: blocks of 14, 10 and 1 uppercase letters, separated by dashes.
+fn is_valid_inchikey(key: &str) -> bool {
+    let blocks: Vec<&str> = key.split('-').collect();
+    matches!(blocks.as_slice(), [first, second, protonation]
+        if [(first, 14), (second, 10), (protonation, 1)].iter().all(|(block, len)| block.len() == *len && block.bytes().all(|b| b.is_ascii_uppercase())))
+}
+
+#[derive(Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize)]
 pub struct SerCompound {
-    pub namespace: String,
+    pub namespace: Namespace,
     pub identifier: String
 }
 impl SerCompound {
-    // Visibility issues forbid this
-    /*pub fn from(cmp: &Compound) -> SerCompound {
-        SerCompound { namespace: cmp.namespace.to_string(), identifier: cmp.identifier.to_string() }
-    }*/
+    /// Key for a `Compound` built from `namespace` and `identifier`. `Compound` keeps its fields private, so they have to be passed along; `to_compound` turns the key back into an equivalent `Compound`.
+    pub fn from_compound(namespace: Namespace, identifier: impl Into<String>) -> SerCompound {
+        SerCompound { namespace, identifier: identifier.into() }
+    }
     pub fn new(id: u32) -> Self {
         Self {
-            namespace: String::from("cid"),
+            namespace: Namespace::Cid,
             identifier: id.to_string(),
         }
     }
     pub fn with_name(name: &str) -> Self {
         Self {
-            namespace: String::from("name"),
+            namespace: Namespace::Name,
             identifier: name.to_string(),
         }
     }
     pub fn with_smiles(smiles: &str) -> Self {
         Self {
-            namespace: String::from("smiles"),
+            namespace: Namespace::Smiles,
             identifier: smiles.to_string(),
         }
     }
     pub fn with_inchi(inchi: &str) -> Self {
         Self {
-            namespace: String::from("inchi"),
+            namespace: Namespace::InChI,
             identifier: inchi.to_string(),
         }
     }
+    /// Like `with_inchi`, but trims surrounding whitespace and rejects strings that don't start with a standard (`InChI=1S/`) or non-standard (`InChI=1/`) InChI prefix, have nothing after it, or contain control characters.
+    pub fn try_with_inchi(inchi: &str) -> Result<Self, CacheError> {
+        let inchi = inchi.trim();
+        let layers = inchi.split_once('/').map_or("", |(_, layers)| layers);
+        match InchiKind::of(inchi) {
+            Some(_) if !layers.is_empty() && !inchi.chars().any(char::is_control) => Ok(SerCompound::with_inchi(inchi)),
+            _ => Err(CacheError::InvalidIdentifier(inchi.to_owned()))
+        }
+    }
     pub fn with_inchikey(inchikey: &str) -> Self {
         Self {
-            namespace: String::from("inchikey"),
+            namespace: Namespace::InChIKey,
             identifier: inchikey.to_string(),
         }
     }
-    pub fn with_nmsp_iden(namespace: &str, identifier: &str) -> SerCompound{
-        SerCompound { namespace: namespace.to_owned(), identifier: identifier.to_owned() }
+    pub fn with_nmsp_iden(namespace: Namespace, identifier: &str) -> SerCompound{
+        SerCompound { namespace, identifier: identifier.to_owned() }
+    }
+    /// Like `with_nmsp_iden`, but parses the namespace and trims surrounding whitespace from both parts. Rejects unknown namespaces, and identifiers that are empty or contain control characters (e.g. a NUL from a bad paste).
+    pub fn try_with_nmsp_iden(namespace: &str, identifier: &str) -> Result<SerCompound, CacheError> {
+        let namespace = namespace.trim().parse()?;
+        let identifier = identifier.trim();
+        if identifier.is_empty() || identifier.chars().any(char::is_control) {
+            return Err(CacheError::InvalidIdentifier(identifier.to_owned()));
+        }
+        Ok(SerCompound::with_nmsp_iden(namespace, identifier))
+    }
+    /// Builds a new `Compound`, parsing the identifier for the `cid` namespace and checking the syntax of SMILES and InChIKeys. `CompoundCache` calls this once per fetch and once per miss in `plan`, never on a cache hit.
+    pub fn to_compound(&self) -> Result<Compound, CacheError> {
+        match self.namespace {
+            Namespace::Cid => {
+                let cid = self.identifier.parse::<u32>().map_err(|_| CacheError::InvalidCid(self.identifier.clone()))?;
+                Ok(Compound::new(cid))
+            }
+            Namespace::Name => Ok(Compound::with_name(&self.identifier)),
+            Namespace::Smiles if !smiles::is_valid_smiles(&self.identifier) => Err(CacheError::InvalidSmiles(self.identifier.clone())),
+            Namespace::Smiles => Ok(Compound::with_smiles(&self.identifier)),
+            Namespace::InChI => Ok(Compound::with_inchi(&self.identifier)),
+            Namespace::InChIKey if !is_valid_inchikey(&self.identifier) => Err(CacheError::InvalidInchiKey(self.identifier.clone())),
+            Namespace::InChIKey => Ok(Compound::with_inchikey(&self.identifier))
+        }
+    }
+}
+
+/// `namespace:identifier`, e.g. `smiles:O`.
+impl fmt::Display for SerCompound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.namespace, self.identifier)
+    }
+}
+impl FromStr for SerCompound {
+    type Err = CacheError;
+    /// Parses the `namespace:identifier` form written by `Display`, checked like `try_with_nmsp_iden` (and `try_with_inchi` for InChIs). The namespace ignores ASCII case; the identifier is everything after the first colon.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (namespace, identifier) = s.split_once(':').ok_or_else(|| CacheError::InvalidIdentifier(s.to_owned()))?;
+        let cmp = SerCompound::try_with_nmsp_iden(namespace, identifier)?;
+        match cmp.namespace {
+            Namespace::InChI => SerCompound::try_with_inchi(&cmp.identifier),
+            _ => Ok(cmp)
+        }
+    }
+}
+
+/// Result of `CompoundCache::plan`, in input order.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct FetchPlan {
+    pub hits: Vec<SerCompound>,
+    /// Cached, but expired or missing some configured properties, so `store` would fetch them again.
+    pub stale: Vec<SerCompound>,
+    pub misses: Vec<SerCompound>,
+    pub invalid: Vec<SerCompound>
+}
+
+/// Result of `CompoundCache::summarize`. Its `Display` is a one-line overview, e.g. for a startup log.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct CacheSummary {
+    pub entries: usize,
+    pub aliases: usize,
+    /// Entries per namespace. Namespaces without entries are left out.
+    pub by_namespace: BTreeMap<Namespace, usize>,
+    /// Share of the entries' properties that have a value, from 0 to 1. 0 for an empty cache.
+    pub completeness: f64,
+    /// See `CompoundCache::estimated_json_bytes`.
+    pub estimated_bytes: usize,
+    /// Age of the entry stored or touched longest ago.
+    pub oldest: Option<Duration>,
+    /// Age of the entry stored or touched most recently.
+    pub newest: Option<Duration>
+}
+impl fmt::Display for CacheSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} entries (", self.entries)?;
+        for (i, (ns, count)) in self.by_namespace.iter().enumerate() {
+            write!(f, "{}{ns}: {count}", if i == 0 { "" } else { ", " })?;
+        }
+        write!(f, "), {} aliases, {:.0}% complete, ~{} bytes", self.aliases, self.completeness * 100.0, self.estimated_bytes)?;
+        if let (Some(oldest), Some(newest)) = (self.oldest, self.newest) {
+            write!(f, ", ages {}s to {}s", newest.as_secs(), oldest.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+/// Generates both well-formed and garbage identifiers, for fuzzing `to_compound`.
+#[cfg(feature = "fuzz")]
+impl<'a> arbitrary::Arbitrary<'a> for SerCompound {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let namespace = *u.choose(Namespace::ALL)?;
+        let identifier: String = if namespace == Namespace::Cid && u.arbitrary()? {
+            u.arbitrary::<u32>()?.to_string()
+        } else {
+            u.arbitrary()?
+        };
+        Ok(SerCompound { namespace, identifier })
+    }
+}
+
+/// Result of `CompoundCache::stats`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub network_fetches: u64
+}
+impl CacheStats {
+    /// Share of lookups that were hits, from 0 to 1. `None` before the first lookup.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        (lookups > 0).then(|| self.hits as f64 / lookups as f64)
+    }
+}
+
+/// Which entry `CompoundCache::merge` keeps when both caches have one under the same key.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MergeStrategy {
+    KeepExisting,
+    Overwrite
+}
+
+/// Result of `CompoundCache::merge`, counting the other cache's entries.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct MergeSummary {
+    pub added: usize,
+    /// Entries that replaced one under the same key (`MergeStrategy::Overwrite`).
+    pub overwritten: usize,
+    /// Entries dropped because the key was taken (`MergeStrategy::KeepExisting`).
+    pub skipped: usize
+}
+
+/// Result of `CompoundCache::exact_mass_report`.
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct MassReport {
+    pub exact_mass: f64,
+    /// Compounds with `isotope_atom_count > 0`, in input order.
+    pub isotope_labeled: Vec<SerCompound>
+}
+
+/// Options for `CompoundCache::deserialize_with_options`.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct DeserializeOptions {
+    /// Re-key every entry to its CID (from the stored properties, no request is made), keeping the original keys as aliases. Entries sharing a CID are merged, keeping the first.
+    pub canonicalize_to_cid: bool
+}
+
+/// A step taken by `CompoundCache::get_traced`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ResolutionStep {
+    /// The key was cached directly.
+    CacheHit(SerCompound),
+    /// The key is an alias of a cached primary key.
+    Alias { alias: SerCompound, primary: SerCompound },
+    /// The key was found in the read-only baseline.
+    Baseline(SerCompound),
+    /// The key was missing and fetched from PubChem.
+    Fetch(SerCompound),
+    /// An alias was registered: the fetched compound's CID for the key, or a merged CID for the one PubChem redirected it to.
+    AliasRegistered { alias: SerCompound, primary: SerCompound }
+}
+
+/// Key type of a `CompoundCache`. `SerCompound` is the default; implement this for your own type to cache compounds that aren't in PubChem.
+///
+/// Fetching from PubChem, serialization, and the other namespace-aware operations are only available on `CompoundCache<SerCompound>`.
+pub trait CacheKey: Clone + Eq + Hash + fmt::Debug {}
+impl CacheKey for SerCompound {}
+
+/// User-supplied key canonicalization, applied to every key on insert and lookup.
+pub struct KeyNormalizer<K>(Box<dyn Fn(K) -> K + Send + Sync>);
+impl<K> fmt::Debug for KeyNormalizer<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyNormalizer")
+    }
+}
+
+/// A cached compound's properties, along with bookkeeping about them.
+#[derive(Debug)]
+struct CachedEntry {
+    props: Properties,
+    /// When the properties were stored, or last confirmed valid with `touch`.
+    fetched_at: SystemTime,
+    /// Warnings PubChem attached to individual property values.
+    annotations: HashMap<CompoundProperty, String>,
+    /// 2D structure depiction, fetched on first use by `get_image`.
+    image_png: Option<Vec<u8>>,
+    /// The key the properties were fetched by. `None` for inserted entries.
+    source: Option<SerCompound>,
+    /// How often the entry was read through `get_ref`, `get`, and the like. Atomic so reads can count through `&self`.
+    access_count: AtomicU64,
+    /// The properties that were fetched, so a `None` among them means PubChem had no value rather than that it wasn't asked for. For inserted entries, the properties that have a value.
+    fetched: HashSet<CompoundProperty>
+}
+impl CachedEntry {
+    fn new(props: Properties) -> CachedEntry {
+        let fetched = populated_properties(&props).collect();
+        CachedEntry { props, fetched_at: SystemTime::now(), annotations: HashMap::new(), image_png: None, source: None, access_count: AtomicU64::new(0), fetched }
     }
-    pub fn to_compound(&self) -> Result<Option<Compound>, Box<dyn Error>> {
-        match &self.namespace[..] {
-            "cid" => Ok(Some(Compound::new(self.identifier.parse::<u32>()?))),
-            "name" => Ok(Some(Compound::with_name(&self.identifier))),
-            "smiles" => Ok(Some(Compound::with_smiles(&self.identifier))),
-            "inchi" => Ok(Some(Compound::with_inchi(&self.identifier))),
-            "inchikey" => Ok(Some(Compound::with_inchikey(&self.identifier))),
-            _ => Ok(None)
+}
+impl Clone for CachedEntry {
+    fn clone(&self) -> Self {
+        CachedEntry {
+            props: propclone(&self.props),
+            fetched_at: self.fetched_at,
+            annotations: self.annotations.clone(),
+            image_png: self.image_png.clone(),
+            source: self.source.clone(),
+            access_count: AtomicU64::new(self.access_count.load(Ordering::Relaxed)),
+            fetched: self.fetched.clone()
         }
     }
 }
+impl From<FetchedProperties> for CachedEntry {
+    fn from(fetched: FetchedProperties) -> Self {
+        CachedEntry { annotations: fetched.annotations, ..CachedEntry::new(fetched.props) }
+    }
+}
 
+/// What a `PropertyFetcher` returns for one compound.
 #[derive(Debug)]
-pub struct CompoundCache {
-    cache: HashMap<SerCompound, Properties>
+pub struct FetchedProperties {
+    pub props: Properties,
+    /// Warnings or errors attached to individual property values (e.g. "could not be standardized").
+    pub annotations: HashMap<CompoundProperty, String>
 }
-impl CompoundCache {
-    pub fn new() -> CompoundCache {
-        CompoundCache { cache: HashMap::new() }
+impl From<Properties> for FetchedProperties {
+    fn from(props: Properties) -> Self {
+        FetchedProperties { props, annotations: HashMap::new() }
+    }
+}
+
+/// Where a `CompoundCache` gets properties from on a miss. The default, `PubChemFetcher`, queries PubChem; swap in your own to proxy or mock requests.
+///
+/// Fetchers must be `Send + Sync` so that a cache can be shared between threads.
+pub trait PropertyFetcher: Send + Sync {
+    fn fetch(&self, cmp: &SerCompound, properties: &[CompoundProperty]) -> Result<FetchedProperties, CacheError>;
+    /// PNG bytes of the compound's 2D structure depiction. Unsupported unless overridden.
+    fn fetch_image(&self, cmp: &SerCompound) -> Result<Vec<u8>, CacheError> {
+        let _ = cmp;
+        Err(CacheError::Unsupported("PNG depictions"))
     }
-    /// Use overwrite for overwriting, this will not insert if value exists. If the compound namespaces are not the same, then the compound properties will be duplicated.
-    pub fn store(&mut self, cmp: SerCompound) -> Result<(), Box<dyn Error>> {
-        if !self.cache.contains_key(&cmp) {
-            let props = cmp.to_compound()?.ok_or(String::new())?.properties(ALL_PROPERTIES)?;
-            self.cache.insert(cmp, props);
+    /// Fetches several CIDs in one go, returning one result per compound, in order. `CompoundCache::get_many` calls this once for all its uncached CIDs. By default each compound is fetched separately with `fetch`; override it if the source accepts lists of CIDs.
+    fn fetch_many(&self, cmps: &[SerCompound], properties: &[CompoundProperty]) -> Vec<Result<FetchedProperties, CacheError>> {
+        cmps.iter().map(|cmp| self.fetch(cmp, properties)).collect()
+    }
+}
+
+/// Fetches properties through the `pubchem` crate. It does not expose per-property annotations or depictions, so no annotations are reported and `fetch_image` is unsupported.
+///
+/// PubChem's server-side faults (busy, timeout, internal error) are reported as `CacheError::Status` with their HTTP status, so `with_retry` retries them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct PubChemFetcher;
+impl PropertyFetcher for PubChemFetcher {
+    fn fetch(&self, cmp: &SerCompound, properties: &[CompoundProperty]) -> Result<FetchedProperties, CacheError> {
+        let compound = cmp.to_compound()?;
+        match compound.properties(properties) {
+            Ok(props) => Ok(props.into()),
+            Err(e) => Err(fault_status(&e.to_string()).map_or(CacheError::Network(e), CacheError::Status))
         }
-        Ok(())
     }
-    /// Overwrites properties.
-    pub fn overwrite(&mut self, cmp: SerCompound) -> Result<(), Box<dyn Error>>{
-        let props = cmp.to_compound()?.ok_or(String::new())?.properties(ALL_PROPERTIES)?;
-        self.cache.insert(cmp, props);
-        Ok(())
+}
+
+/// PUG REST fault codes of server-side failures, with the HTTP status PubChem sends them with.
+const SERVER_FAULTS: [(&str, u16); 3] = [("PUGREST.ServerBusy", 503), ("PUGREST.Timeout", 504), ("PUGREST.ServerError", 500)];
+
+/// The HTTP status of the server-side fault `message` reports, if any. The `pubchem` crate only passes fault codes on in its error messages.
+fn fault_status(message: &str) -> Option<u16> {
+    SERVER_FAULTS.iter().find(|(code, _)| message.contains(code)).map(|&(_, status)| status)
+}
+
+/// Shared so `get_async` can hand the fetcher to a blocking task.
+struct BoxedFetcher(Arc<dyn PropertyFetcher>);
+impl fmt::Debug for BoxedFetcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PropertyFetcher")
     }
-    /// If the compound does not exist, the properties are added and returned.
-    pub fn get(&mut self, cmp: SerCompound) -> Result<(bool, &Properties), Box<dyn Error>> {
-        let props = cmp.to_compound()?.ok_or(String::new())?.properties(ALL_PROPERTIES)?;
-        let haskey = self.cache.contains_key(&cmp);
-        if !haskey {
-            self.cache.insert(cmp.clone(), props);
+}
+
+// Keeps `CompoundCache` and `SharedCompoundCache` usable across threads; fails to compile if a field stops being `Send + Sync`.
+const _: () = {
+    fn assert_send_sync<T: Send + Sync>() {}
+    fn assert_all() {
+        assert_send_sync::<CompoundCache>();
+        assert_send_sync::<SharedCompoundCache>();
+    }
+};
+
+/// Where a cache's entries are kept. With the `ordered` feature, they stay in insertion order, so iteration and serialization are reproducible.
+#[cfg(not(feature = "ordered"))]
+type Entries<K> = HashMap<K, CachedEntry>;
+#[cfg(feature = "ordered")]
+type Entries<K> = indexmap::IndexMap<K, CachedEntry>;
+#[cfg(not(feature = "ordered"))]
+type EntriesIter<'a, K> = std::collections::hash_map::Iter<'a, K, CachedEntry>;
+#[cfg(feature = "ordered")]
+type EntriesIter<'a, K> = indexmap::map::Iter<'a, K, CachedEntry>;
+
+#[cfg(not(feature = "ordered"))]
+fn remove_entry<K: CacheKey>(entries: &mut Entries<K>, key: &K) -> Option<CachedEntry> {
+    entries.remove(key)
+}
+/// Shifts the later entries down, keeping their order.
+#[cfg(feature = "ordered")]
+fn remove_entry<K: CacheKey>(entries: &mut Entries<K>, key: &K) -> Option<CachedEntry> {
+    entries.shift_remove(key)
+}
+
+#[derive(Debug)]
+pub struct CompoundCache<K: CacheKey = SerCompound> {
+    cache: Entries<K>,
+    fetch_budget: Option<usize>,
+    namespace_properties: HashMap<Namespace, &'static [CompoundProperty]>,
+    key_normalizer: Option<KeyNormalizer<K>>,
+    /// Maps alternate keys (e.g. the CID of a compound fetched by name) to the primary key the properties are stored under.
+    aliases: HashMap<K, K>,
+    allowed_properties: Option<&'static [CompoundProperty]>,
+    /// When the file this cache was loaded from was written.
+    generated_at: Option<SystemTime>,
+    /// Read-only layer consulted after this one. It is never written to or serialized.
+    baseline: Option<Box<CompoundCache<K>>>,
+    fetcher: BoxedFetcher,
+    /// Exponential moving average of how long fetches take.
+    average_fetch_latency: Option<Duration>,
+    /// How many fetches were made for each namespace.
+    fetches_by_namespace: HashMap<Namespace, u64>,
+    /// Set by every change to the entries or aliases, cleared when the cache is saved.
+    dirty: AtomicBool,
+    /// The file the cache was last loaded from or saved to. While the cache is clean, saving there again is skipped.
+    saved_path: Mutex<Option<PathBuf>>,
+    /// Whether failed fetches are remembered in `failed` instead of being retried on the next lookup.
+    negative_caching: bool,
+    /// Keys whose fetch failed, with the error message.
+    failed: HashMap<K, String>,
+    /// Fetches populating fewer properties than this are degraded.
+    min_properties: Option<usize>,
+    /// Whether degraded fetches fail with `CacheError::DegradedResponse` instead of being stored.
+    strict_properties: bool,
+    /// How long entries stay fresh after being stored or touched.
+    ttl: Option<Duration>,
+    /// Keys whose stored properties came from a degraded fetch.
+    degraded: HashSet<K>,
+    /// Spaces out requests to the fetcher, see `with_rate_limit`.
+    rate_limit: Option<RateLimiter>,
+    /// How transient fetch failures are retried, see `with_retry`.
+    retry: Option<RetryPolicy>,
+    stats: StatCounters
+}
+
+/// Counters behind `CompoundCache::stats`. Atomic so lookups can count through `&self`.
+#[derive(Debug, Default)]
+struct StatCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    network_fetches: AtomicU64
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration
+}
+impl<K: CacheKey> CompoundCache<K> {
+    /// An empty cache. For `SerCompound` keys, `new` does the same.
+    pub fn empty() -> CompoundCache<K> {
+        CompoundCache {
+            cache: Entries::new(),
+            fetch_budget: None,
+            namespace_properties: HashMap::new(),
+            key_normalizer: None,
+            aliases: HashMap::new(),
+            allowed_properties: None,
+            generated_at: None,
+            baseline: None,
+            fetcher: BoxedFetcher(Arc::new(PubChemFetcher)),
+            average_fetch_latency: None,
+            fetches_by_namespace: HashMap::new(),
+            dirty: AtomicBool::new(false),
+            saved_path: Mutex::new(None),
+            negative_caching: false,
+            failed: HashMap::new(),
+            min_properties: None,
+            strict_properties: false,
+            ttl: None,
+            degraded: HashSet::new(),
+            rate_limit: None,
+            retry: None,
+            stats: StatCounters::default()
+        }
+    }
+    /// Whether entries or aliases changed since the cache was created, loaded, or last saved.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Relaxed)
+    }
+    fn mark_dirty(&mut self) {
+        *self.dirty.get_mut() = true;
+    }
+    /// Clears the dirty flag once the cache matches the file at `path`. `None` for saves that aren't a single file the cache can be saved to again, like `save_sharded`.
+    pub(crate) fn mark_saved(&self, path: Option<&Path>) {
+        self.dirty.store(false, Ordering::Relaxed);
+        *self.saved_path.lock().unwrap_or_else(PoisonError::into_inner) = path.map(Path::to_owned);
+    }
+    /// Whether `path` already holds the cache as it is, so saving there can be skipped.
+    fn is_saved_at(&self, path: &Path) -> bool {
+        !self.is_dirty() && self.saved_path.lock().unwrap_or_else(PoisonError::into_inner).as_deref() == Some(path)
+    }
+    /// When the file this cache was deserialized from was written, if it recorded it.
+    pub fn generated_at(&self) -> Option<SystemTime> {
+        self.generated_at
+    }
+    /// Only keeps the given properties; any other field is set to `None` when an entry is inserted.
+    pub fn with_allowed_properties(mut self, allowed: &'static [CompoundProperty]) -> CompoundCache<K> {
+        self.allowed_properties = Some(allowed);
+        self
+    }
+    fn strip_disallowed(&self, props: &mut Properties) {
+        if let Some(allowed) = self.allowed_properties {
+            for property in ALL_PROPERTIES.iter().filter(|property| !allowed.contains(property)) {
+                clear_property(props, property);
+            }
+        }
+    }
+    /// Applies `normalizer` to every key on insert and lookup, e.g. to make names case-insensitive. Keys already in the cache are not rewritten.
+    pub fn with_key_normalizer(mut self, normalizer: impl Fn(K) -> K + Send + Sync + 'static) -> CompoundCache<K> {
+        self.key_normalizer = Some(KeyNormalizer(Box::new(normalizer)));
+        self
+    }
+    fn normalize(&self, cmp: K) -> K {
+        match &self.key_normalizer {
+            Some(KeyNormalizer(f)) => f(cmp),
+            None => cmp
+        }
+    }
+    fn normalized<'a>(&self, cmp: &'a K) -> Cow<'a, K> {
+        match &self.key_normalizer {
+            Some(KeyNormalizer(f)) => Cow::Owned(f(cmp.clone())),
+            None => Cow::Borrowed(cmp)
+        }
+    }
+    /// Finds the primary key a normalized key refers to, either directly or through an alias.
+    fn resolve(&self, cmp: &K) -> Option<&K> {
+        if let Some((key, _)) = self.cache.get_key_value(cmp) {
+            return Some(key);
+        }
+        let primary = self.aliases.get(cmp)?;
+        self.cache.get_key_value(primary).map(|(key, _)| key)
+    }
+    fn lookup(&self, cmp: &K) -> Option<&Properties> {
+        self.lookup_normalized(self.normalized(cmp).as_ref())
+    }
+    /// Looks a normalized key up in this layer, then in the baseline.
+    fn lookup_normalized(&self, cmp: &K) -> Option<&Properties> {
+        match self.resolve(cmp) {
+            Some(key) => Some(&self.cache[key].props),
+            None => self.baseline.as_ref()?.lookup(cmp)
+        }
+    }
+    /// Like `lookup_normalized`, but counts the access on the entry found.
+    fn access_normalized(&self, cmp: &K) -> Option<&Properties> {
+        match self.resolve(cmp) {
+            Some(key) => {
+                let entry = &self.cache[key];
+                entry.access_count.fetch_add(1, Ordering::Relaxed);
+                Some(&entry.props)
+            }
+            None => self.baseline.as_ref()?.access_normalized(cmp)
         }
-        Ok((haskey, &self.cache[&cmp]))
+    }
+    /// Layers this cache over a read-only `baseline`. Lookups fall back to the baseline; inserts and serialization only touch this cache.
+    pub fn with_baseline(mut self, baseline: CompoundCache<K>) -> CompoundCache<K> {
+        self.baseline = Some(Box::new(baseline));
+        self
+    }
+    pub fn baseline(&self) -> Option<&CompoundCache<K>> {
+        self.baseline.as_deref()
+    }
+    /// The cached properties, from this cache or its baseline. Does not make a PubChem request.
+    pub fn get_ref(&self, cmp: &K) -> Option<&Properties> {
+        self.access_normalized(self.normalized(cmp).as_ref())
     }
     /// If the compound does not exist, None is returned. Does not make a PubChem request.
-    pub fn get_noreq(&self, cmp: SerCompound) -> Result<Option<&Properties>, pubchem::error::Error> {
-        if self.cache.contains_key(&cmp) {
-            Ok(Some(&self.cache[&cmp]))
-        } else {
-            Ok(None)
-        }
-    }
-
-    pub fn insert(&mut self, key: SerCompound, val: Properties) {
-        self.cache.insert(key, val);
-    }
-
-    pub fn contains(&self, key: &SerCompound) -> bool {
-        self.cache.contains_key(key)
-    }
-
-    pub fn serialize(&self) -> Result<Value, String> {
-        let mut arr: Vec<Value> = Vec::new();
-        for (cmp, prop) in &self.cache {
-            let mut ser_obj = Map::new();
-            ser_obj.insert("namespace".to_owned(), Value::String(cmp.namespace.clone()));
-            ser_obj.insert("identifier".to_owned(), Value::String(cmp.identifier.clone()));
-            let mut properties = Map::new();
-            {
-                // big property 2 electrig boogaloo
-                properties.insert("atom_stereo_count".to_owned(), prop.atom_stereo_count.unwrap().into());
-                properties.insert("bond_stereo_count".to_owned(), prop.bond_stereo_count.unwrap().into());
-                properties.insert("canonical_smiles".to_owned(), Value::String(prop.canonical_smiles.as_ref().unwrap().clone()));
-                properties.insert("charge".to_owned(), prop.charge.unwrap().into());
-                properties.insert("cid".to_owned(), prop.cid.into());
-                properties.insert("complexity".to_owned(), prop.complexity.unwrap().into());
-                properties.insert("conformer_count_3d".to_owned(), prop.conformer_count_3d.unwrap().into());
-                properties.insert("conformer_model_rmsd_3d".to_owned(), prop.conformer_model_rmsd_3d.unwrap().into());
-                properties.insert("covalent_unit_count".to_owned(), prop.covalent_unit_count.unwrap().into());
-                properties.insert("defined_atom_stereo_count".to_owned(), prop.defined_atom_stereo_count.unwrap().into());
-                properties.insert("defined_bond_stereo_count".to_owned(), prop.defined_bond_stereo_count.unwrap().into());
-                properties.insert("effective_rotor_count_3d".to_owned(), prop.effective_rotor_count_3d.unwrap().into());
-                properties.insert("exact_mass".to_owned(), Value::String(prop.exact_mass.as_ref().unwrap().clone()));
-                properties.insert("feature_acceptor_count_3d".to_owned(), prop.feature_acceptor_count_3d.unwrap().into());
-                properties.insert("feature_anion_count_3d".to_owned(), prop.feature_anion_count_3d.unwrap().into());
-                properties.insert("feature_cation_count_3d".to_owned(), prop.feature_cation_count_3d.unwrap().into());
-                properties.insert("feature_count_3d".to_owned(), prop.feature_count_3d.unwrap().into());
-                properties.insert("feature_donor_count_3d".to_owned(), prop.feature_donor_count_3d.unwrap().into());
-                properties.insert("feature_hydrophobe_count_3d".to_owned(), prop.feature_hydrophobe_count_3d.unwrap().into());
-                properties.insert("feature_ring_count_3d".to_owned(), prop.feature_ring_count_3d.unwrap().into());
-                properties.insert("fingerprint_2d".to_owned(), Value::String(prop.fingerprint_2d.as_ref().unwrap().clone()));
-                properties.insert("hbond_acceptor_count".to_owned(), prop.hbond_acceptor_count.unwrap().into());
-                properties.insert("hbond_donor_count".to_owned(), prop.hbond_donor_count.unwrap().into());
-                properties.insert("heavy_atom_count".to_owned(), prop.heavy_atom_count.unwrap().into());
-                properties.insert("inchi".to_owned(), Value::String(prop.inchi.as_ref().unwrap().clone()));
-                properties.insert("inchi_key".to_owned(), Value::String(prop.inchi_key.as_ref().unwrap().clone()));
-                properties.insert("isomeric_smiles".to_owned(), Value::String(prop.isomeric_smiles.as_ref().unwrap().clone()));
-                properties.insert("isotope_atom_count".to_owned(), prop.isotope_atom_count.unwrap().into());
-                properties.insert("iupac_name".to_owned(), valify_string_op(&prop.iupac_name));
-                properties.insert("molecular_formula".to_owned(), Value::String(prop.molecular_formula.as_ref().unwrap().clone()));
-                properties.insert("molecular_weight".to_owned(), Value::String(prop.molecular_weight.as_ref().unwrap().clone()));
-                properties.insert("monoisotopic_mass".to_owned(), Value::String(prop.monoisotopic_mass.as_ref().unwrap().clone()));
-                properties.insert("rotatable_bond_count".to_owned(), prop.rotatable_bond_count.unwrap().into());
-                properties.insert("title".to_owned(), Value::String(prop.title.as_ref().unwrap().clone()));
-                properties.insert("tpsa".to_owned(), prop.tpsa.unwrap().into());
-                properties.insert("undefined_atom_stereo_count".to_owned(), prop.undefined_atom_stereo_count.unwrap().into());
-                properties.insert("undefined_bond_stereo_count".to_owned(), prop.undefined_bond_stereo_count.unwrap().into());
-                properties.insert("volume_3d".to_owned(), prop.volume_3d.unwrap().into());
-                properties.insert("x_steric_quadrupole_3d".to_owned(), prop.x_steric_quadrupole_3d.unwrap().into());
-                properties.insert("xlogp".to_owned(), prop.xlogp.unwrap().into());
-                properties.insert("y_steric_quadrupole_3d".to_owned(), prop.y_steric_quadrupole_3d.unwrap().into());
-                properties.insert("z_steric_quadrupole_3d".to_owned(), prop.z_steric_quadrupole_3d.unwrap().into());
-            }
-            ser_obj.insert("properties".to_owned(), Value::Object(properties));
-            arr.push(Value::Object(ser_obj));
+    #[deprecated(note = "use `get_ref`, which never fails")]
+    pub fn get_noreq(&self, cmp: K) -> Result<Option<&Properties>, pubchem::error::Error> {
+        let props = self.get_ref(&cmp);
+        self.record_lookup(props.is_some());
+        Ok(props)
+    }
+    /// Counts a lookup by `get` and the like for `stats`.
+    pub(crate) fn record_lookup(&self, hit: bool) {
+        let counter = if hit { &self.stats.hits } else { &self.stats.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Hits and misses of `get`, `get_noreq`, `get_async` and `SharedCompoundCache::get`, and how many requests the fetcher was sent (retries included). Counted since the cache was created or `reset_stats` was called.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.stats.hits.load(Ordering::Relaxed),
+            misses: self.stats.misses.load(Ordering::Relaxed),
+            network_fetches: self.stats.network_fetches.load(Ordering::Relaxed)
         }
-        let mut map = Map::new();
-        map.insert("cache".to_owned(), Value::Array(arr));
-        Ok(Value::Object(map))
+    }
+    pub fn reset_stats(&mut self) {
+        self.stats = StatCounters::default();
     }
 
-    pub fn deserialize(st: String) -> Result<CompoundCache, String> {
-        let mut output_cache = CompoundCache::new();
-        match serde_json::from_str::<Value>(&st[..]) {
-            Ok(root) => {
-                let cache = root
-                    .as_object().ok_or("The root JSON was not an object!")?
-                    .get("cache").ok_or("`cache` could not be found! Make sure it's an array in the root object!")?
-                    .as_array().ok_or("`cache` was not an array!")?;
-                for i in cache {
-                    match i {
-                        Value::Object(entry) => {
-                            let obj = &i["properties"];
-                            let properties = Properties {
-                                // big property 3: deser
-                                atom_stereo_count: Some(obj["atom_stereo_count"].as_i64().unwrap() as i32),
-                                bond_stereo_count: Some(obj["bond_stereo_count"].as_i64().unwrap() as i32),
-                                canonical_smiles: Some(obj["canonical_smiles"].as_str().clone().unwrap().to_owned()),
-                                charge: Some(obj["charge"].as_i64().unwrap() as i32),
-                                cid: obj["cid"].as_i64().unwrap() as i32,
-                                complexity: Some(obj["complexity"].as_i64().unwrap() as i32),
-                                conformer_count_3d: Some(obj["conformer_count_3d"].as_i64().unwrap() as i32),
-                                conformer_model_rmsd_3d: Some(obj["conformer_model_rmsd_3d"].as_f64().unwrap()),
-                                covalent_unit_count: Some(obj["covalent_unit_count"].as_i64().unwrap() as i32),
-                                defined_atom_stereo_count: Some(obj["defined_atom_stereo_count"].as_i64().unwrap() as i32),
-                                defined_bond_stereo_count: Some(obj["defined_bond_stereo_count"].as_i64().unwrap() as i32),
-                                effective_rotor_count_3d: Some(obj["effective_rotor_count_3d"].as_f64().unwrap()),
-                                exact_mass: Some(obj["exact_mass"].as_str().unwrap().to_owned()),
-                                feature_acceptor_count_3d: Some(obj["feature_acceptor_count_3d"].as_i64().unwrap() as i32),
-                                feature_anion_count_3d: Some(obj["feature_anion_count_3d"].as_i64().unwrap() as i32),
-                                feature_cation_count_3d: Some(obj["feature_cation_count_3d"].as_i64().unwrap() as i32),
-                                feature_count_3d: Some(obj["feature_count_3d"].as_i64().unwrap() as i32),
-                                feature_donor_count_3d: Some(obj["feature_donor_count_3d"].as_i64().unwrap() as i32),
-                                feature_hydrophobe_count_3d: Some(obj["feature_hydrophobe_count_3d"].as_i64().unwrap() as i32),
-                                feature_ring_count_3d: Some(obj["feature_ring_count_3d"].as_i64().unwrap() as i32),
-                                fingerprint_2d: Some(obj["fingerprint_2d"].as_str().unwrap().to_owned()),
-                                hbond_acceptor_count: Some(obj["hbond_acceptor_count"].as_i64().unwrap() as i32),
-                                hbond_donor_count: Some(obj["hbond_donor_count"].as_i64().unwrap() as i32),
-                                heavy_atom_count: Some(obj["heavy_atom_count"].as_i64().unwrap() as i32),
-                                inchi: Some(obj["inchi"].as_str().unwrap().to_owned()),
-                                inchi_key: Some(obj["inchi_key"].as_str().unwrap().to_owned()),
-                                isomeric_smiles: Some(obj["isomeric_smiles"].as_str().unwrap().to_owned()),
-                                isotope_atom_count: Some(obj["isotope_atom_count"].as_i64().unwrap() as i32),
-                                iupac_name: obj["iupac_name"].as_str().map(|s|s.to_owned()),
-                                molecular_formula: Some(obj["molecular_formula"].as_str().unwrap().to_owned()),
-                                molecular_weight: Some(obj["molecular_weight"].as_str().unwrap().to_owned()),
-                                monoisotopic_mass: Some(obj["monoisotopic_mass"].as_str().unwrap().to_owned()),
-                                rotatable_bond_count: Some(obj["rotatable_bond_count"].as_i64().unwrap() as i32),
-                                title: Some(obj["title"].as_str().unwrap().to_owned()),
-                                tpsa: Some(obj["tpsa"].as_f64().unwrap()),
-                                undefined_atom_stereo_count: Some(obj["undefined_atom_stereo_count"].as_i64().unwrap() as i32),
-                                undefined_bond_stereo_count: Some(obj["undefined_bond_stereo_count"].as_i64().unwrap() as i32),
-                                volume_3d: Some(obj["volume_3d"].as_f64().unwrap()),
-                                x_steric_quadrupole_3d: Some(obj["x_steric_quadrupole_3d"].as_f64().unwrap()),
-                                xlogp: Some(obj["xlogp"].as_f64().unwrap()),
-                                y_steric_quadrupole_3d: Some(obj["y_steric_quadrupole_3d"].as_f64().unwrap()),
-                                z_steric_quadrupole_3d: Some(obj["z_steric_quadrupole_3d"].as_f64().unwrap())
-                            };
-                            let key = SerCompound {
-                                namespace: entry["namespace"].as_str().unwrap().to_owned(),
-                                identifier: entry["identifier"].as_str().unwrap().to_owned()
-                            };
-                            output_cache.insert(key, properties);
-                        }
-                        _ => Err("Value was not an object!")?
-                    }
-                }
+    /// Inserts properties without fetching. Properties with a non-positive `cid` (e.g. from a not-found response) are rejected.
+    pub fn insert(&mut self, key: K, val: Properties) -> Result<(), CacheError> {
+        check_cid(&val)?;
+        self.insert_unchecked(key, val);
+        Ok(())
+    }
+
+    /// Like `insert`, but accepts any `cid`.
+    pub fn insert_unchecked(&mut self, key: K, mut val: Properties) {
+        let key = self.normalize(key);
+        self.strip_disallowed(&mut val);
+        self.cache.insert(key, CachedEntry::new(val));
+        self.mark_dirty();
+    }
+
+    /// Prepares an entry taken from another cache or file the way `insert` would: normalizes its key and strips disallowed properties.
+    fn admit(&self, key: K, mut entry: CachedEntry) -> (K, CachedEntry) {
+        self.strip_disallowed(&mut entry.props);
+        (self.normalize(key), entry)
+    }
+
+    /// Removes the entry `cmp` resolves to, along with its aliases, returning its properties. The next `store` or `get` fetches it again.
+    pub fn remove(&mut self, cmp: &K) -> Option<Properties> {
+        let key = self.resolve(self.normalized(cmp).as_ref())?.clone();
+        let entry = remove_entry(&mut self.cache, &key)?;
+        self.aliases.retain(|_, primary| *primary != key);
+        self.degraded.remove(&key);
+        self.mark_dirty();
+        Some(entry.props)
+    }
+
+    /// Removes every entry and alias. The baseline and the cache's settings are kept.
+    pub fn clear(&mut self) {
+        if !self.cache.is_empty() || !self.aliases.is_empty() {
+            self.cache.clear();
+            self.aliases.clear();
+            self.degraded.clear();
+            self.mark_dirty();
+        }
+    }
+
+    /// Keeps only the entries for which `f` returns true, e.g. to drop large compounds without refetching anything. Aliases of removed entries are removed too. The baseline is left alone.
+    pub fn retain<F: FnMut(&K, &Properties) -> bool>(&mut self, mut f: F) {
+        let before = self.cache.len();
+        self.cache.retain(|cmp, entry| f(cmp, &entry.props));
+        if self.cache.len() < before {
+            let cache = &self.cache;
+            self.aliases.retain(|_, primary| cache.contains_key(primary));
+            self.degraded.retain(|cmp| cache.contains_key(cmp));
+            self.mark_dirty();
+        }
+    }
+
+    /// Number of stored entries. Aliases and the baseline are not counted.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    /// Number of aliases, i.e. keys that resolve to an entry stored under another key.
+    pub fn alias_count(&self) -> usize {
+        self.aliases.len()
+    }
+
+    /// Drops every alias, keeping the entries themselves. Afterwards, compounds only resolve by the key they are stored under.
+    pub fn clear_aliases(&mut self) {
+        if !self.aliases.is_empty() {
+            self.aliases.clear();
+            self.mark_dirty();
+        }
+    }
+
+    /// Whether `key` resolves to an entry, directly, through an alias, or in the baseline.
+    pub fn contains(&self, key: &K) -> bool {
+        self.lookup(key).is_some()
+    }
+
+    /// Iterates over every stored entry. Aliases and the baseline are not included. The order is unspecified, as the entries are kept in a `HashMap`, unless the `ordered` feature is enabled, in which case it is insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &Properties)> {
+        self.into_iter()
+    }
+
+    /// The entries whose properties satisfy `pred`, e.g. `|props| props.xlogp.is_some_and(|xlogp| xlogp < 0.0)`. Like `iter`, aliases and the baseline are left out and the order is unspecified.
+    pub fn filter<F: Fn(&Properties) -> bool>(&self, pred: F) -> Vec<(&K, &Properties)> {
+        self.iter().filter(|(_, props)| pred(props)).collect()
+    }
+
+    /// Iterates over every entry with mutable access to its properties, e.g. for correcting a field across the whole cache. Keys can't be changed this way.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut Properties)> {
+        self.mark_dirty();
+        self.cache.iter_mut().map(|(cmp, entry)| (cmp, &mut entry.props))
+    }
+
+    /// Marks an entry as still valid without refetching it, resetting its age. Returns whether it existed.
+    pub fn touch(&mut self, cmp: &K) -> bool {
+        let key = match self.resolve(self.normalized(cmp).as_ref()) {
+            Some(key) => key.clone(),
+            None => return false
+        };
+        if let Some(entry) = self.cache.get_mut(&key) {
+            entry.fetched_at = SystemTime::now();
+        }
+        self.mark_dirty();
+        true
+    }
+
+    /// Warnings attached to individual property values when the entry was fetched.
+    pub fn annotations(&self, cmp: &K) -> Option<&HashMap<CompoundProperty, String>> {
+        let key = self.resolve(self.normalized(cmp).as_ref())?;
+        Some(&self.cache[key].annotations)
+    }
+
+    /// Makes entries expire `ttl` after they were stored or last touched. Expired entries are fetched again by `get`, `store`, and the like, and hidden by `get_noreq_fresh`. Entries in the baseline never expire.
+    pub fn with_ttl(mut self, ttl: Duration) -> CompoundCache<K> {
+        self.ttl = Some(ttl);
+        self
+    }
+    /// Sets the time to live, `None` meaning entries never expire.
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+    }
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+    fn is_expired(&self, entry: &CachedEntry) -> bool {
+        self.ttl.is_some_and(|ttl| entry.fetched_at.elapsed().unwrap_or_default() > ttl)
+    }
+    /// Like `get_ref`, but `None` if the entry has expired (see `with_ttl`). Does not make a PubChem request.
+    pub fn get_noreq_fresh(&self, cmp: &K) -> Option<&Properties> {
+        let cmp = self.normalized(cmp);
+        match self.resolve(cmp.as_ref()) {
+            Some(key) if self.is_expired(&self.cache[key]) => None,
+            _ => self.access_normalized(cmp.as_ref())
+        }
+    }
+
+    /// Time since the entry was stored or last touched.
+    pub fn age(&self, cmp: &K) -> Option<Duration> {
+        let key = self.resolve(self.normalized(cmp).as_ref())?;
+        Some(self.cache[key].fetched_at.elapsed().unwrap_or_default())
+    }
+
+    /// How many times the entry was read through `get_ref`, `get`, and the like. Inserting, storing, and `contains` don't count.
+    pub fn access_count(&self, cmp: &K) -> Option<u64> {
+        let key = self.resolve(self.normalized(cmp).as_ref())?;
+        Some(self.cache[key].access_count.load(Ordering::Relaxed))
+    }
+
+    /// Keeps the `keep` most accessed entries and removes the rest, returning how many were removed. Ties go to the most recently stored or touched entry. Aliases and degraded markers of removed entries are removed too.
+    pub fn prune_to_most_accessed(&mut self, keep: usize) -> usize {
+        if self.cache.len() <= keep {
+            return 0;
+        }
+        let mut ranked: Vec<(&K, u64, SystemTime)> = self.cache.iter()
+            .map(|(cmp, entry)| (cmp, entry.access_count.load(Ordering::Relaxed), entry.fetched_at))
+            .collect();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then(b.2.cmp(&a.2)));
+        let removed: HashSet<K> = ranked[keep..].iter().map(|(cmp, _, _)| (*cmp).clone()).collect();
+        for cmp in &removed {
+            remove_entry(&mut self.cache, cmp);
+        }
+        self.aliases.retain(|_, primary| !removed.contains(primary));
+        self.degraded.retain(|cmp| !removed.contains(cmp));
+        self.mark_dirty();
+        removed.len()
+    }
+
+    /// Entries whose keys are also in `other`, with properties taken from `self`.
+    pub fn intersection(&self, other: &CompoundCache<K>) -> CompoundCache<K> {
+        let mut output_cache = CompoundCache::empty();
+        for (cmp, entry) in self.cache.iter().filter(|(cmp, _)| other.contains(cmp)) {
+            output_cache.cache.insert(cmp.clone(), entry.clone());
+        }
+        output_cache
+    }
+
+    /// Entries whose keys are not in `other`.
+    pub fn difference(&self, other: &CompoundCache<K>) -> CompoundCache<K> {
+        let mut output_cache = CompoundCache::empty();
+        for (cmp, entry) in self.cache.iter().filter(|(cmp, _)| !other.contains(cmp)) {
+            output_cache.cache.insert(cmp.clone(), entry.clone());
+        }
+        output_cache
+    }
+
+    /// Moves the entries and aliases of `other` into this cache, e.g. to combine imports run on separate machines. When both have an entry under the same key, `strategy` decides which one is kept. Entries keep their fetch time and other bookkeeping, but go through this cache's key normalizer and property allow-list like inserted ones. Aliases whose primary isn't in this cache afterwards are dropped.
+    pub fn merge(&mut self, other: CompoundCache<K>, strategy: MergeStrategy) -> MergeSummary {
+        let mut summary = MergeSummary::default();
+        for (key, entry) in other.cache {
+            let degraded = other.degraded.contains(&key);
+            let (key, entry) = self.admit(key, entry);
+            let exists = self.cache.contains_key(&key);
+            if exists && strategy == MergeStrategy::KeepExisting {
+                summary.skipped += 1;
+                continue;
+            }
+            if degraded {
+                self.degraded.insert(key.clone());
+            } else {
+                self.degraded.remove(&key);
+            }
+            self.aliases.remove(&key);
+            self.cache.insert(key, entry);
+            if exists {
+                summary.overwritten += 1;
+            } else {
+                summary.added += 1;
             }
-            Err(e) => Err(format!("Could not parse JSON! ({e})"))?
         }
-        Ok(output_cache)
+        for (alias, primary) in other.aliases {
+            let (alias, primary) = (self.normalize(alias), self.normalize(primary));
+            if !self.cache.contains_key(&primary) || self.cache.contains_key(&alias) || (strategy == MergeStrategy::KeepExisting && self.aliases.contains_key(&alias)) {
+                continue;
+            }
+            self.aliases.insert(alias, primary);
+        }
+        if summary.added + summary.overwritten > 0 {
+            self.mark_dirty();
+        }
+        summary
+    }
+}
+impl<'a, K: CacheKey> IntoIterator for &'a CompoundCache<K> {
+    type Item = (&'a K, &'a Properties);
+    type IntoIter = Iter<'a, K>;
+    fn into_iter(self) -> Iter<'a, K> {
+        Iter(self.cache.iter())
     }
 }
 
-pub fn valify_string_op(string: &Option<String>) -> Value {
-    match string {
-        Some(st) => Value::String(st.clone()),
-        None => Value::Null
+impl<K: CacheKey> Default for CompoundCache<K> {
+    fn default() -> Self {
+        CompoundCache::empty()
     }
 }
 
-pub fn propclone(props: &Properties) -> Properties {
-    Properties {
-        cid: props.cid,
-        molecular_formula: props.molecular_formula.clone(),
-        molecular_weight: props.molecular_weight.clone(),
-        canonical_smiles: props.canonical_smiles.clone(),
-        isomeric_smiles: props.isomeric_smiles.clone(),
-        inchi: props.inchi.clone(),
-        inchi_key: props.inchi_key.clone(),
-        iupac_name: props.iupac_name.clone(),
-        xlogp: props.xlogp.clone(),
+/// Inserts every pair with `insert_unchecked`, so properties with a non-positive `cid` are kept.
+impl<K: CacheKey> FromIterator<(K, Properties)> for CompoundCache<K> {
+    fn from_iter<I: IntoIterator<Item = (K, Properties)>>(iter: I) -> Self {
+        let mut output_cache = CompoundCache::empty();
+        for (key, props) in iter {
+            output_cache.insert_unchecked(key, props);
+        }
+        output_cache
+    }
+}
+
+/// Iterator over the entries of a `CompoundCache`, see `CompoundCache::iter`.
+#[derive(Debug)]
+pub struct Iter<'a, K>(EntriesIter<'a, K>);
+
+impl<'a, K> Iterator for Iter<'a, K> {
+    type Item = (&'a K, &'a Properties);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(cmp, entry)| (cmp, &entry.props))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl CompoundCache {
+    pub fn new() -> CompoundCache {
+        CompoundCache::empty()
+    }
+    /// An empty cache layered over the compounds in `path`, which is never rewritten (see `with_baseline`).
+    pub fn with_seed_file<P: AsRef<Path>>(path: P) -> Result<CompoundCache, CacheError> {
+        Ok(CompoundCache::new().with_baseline(format::load(path, Some(&JsonFormat))?))
+    }
+    /// Sets the properties fetched for compounds in namespace `ns`. Namespaces without a set fetch `ALL_PROPERTIES`.
+    pub fn with_namespace_properties(mut self, ns: Namespace, props: &'static [CompoundProperty]) -> CompoundCache {
+        self.set_namespace_properties(ns, props);
+        self
+    }
+    pub fn set_namespace_properties(&mut self, ns: Namespace, props: &'static [CompoundProperty]) {
+        self.namespace_properties.insert(ns, props);
+    }
+    /// The properties that will be fetched for compounds in namespace `ns`.
+    pub fn properties_for(&self, ns: Namespace) -> &'static [CompoundProperty] {
+        self.namespace_properties.get(&ns).copied().unwrap_or(ALL_PROPERTIES)
+    }
+    /// Replaces where properties are fetched from on a miss (`PubChemFetcher` by default).
+    pub fn with_fetcher(mut self, fetcher: impl PropertyFetcher + 'static) -> CompoundCache {
+        self.set_fetcher(fetcher);
+        self
+    }
+    pub fn set_fetcher(&mut self, fetcher: impl PropertyFetcher + 'static) {
+        self.fetcher = BoxedFetcher(Arc::new(fetcher));
+    }
+    /// Caps the number of fetches this cache will make. Once spent, fetches fail with `CacheError::BudgetExceeded`.
+    pub fn with_fetch_budget(mut self, budget: usize) -> CompoundCache {
+        self.fetch_budget = Some(budget);
+        self
+    }
+    /// Sets the remaining fetch budget, `None` meaning unlimited.
+    pub fn set_fetch_budget(&mut self, budget: Option<usize>) {
+        self.fetch_budget = budget;
+    }
+    /// Remaining fetch budget, `None` meaning unlimited.
+    pub fn fetch_budget(&self) -> Option<usize> {
+        self.fetch_budget
+    }
+    /// Limits requests to the fetcher to `per_second` (at least one), sleeping before a request when needed. PubChem rejects clients sending more than 5 requests per second. Requests are spaced evenly, `1 / per_second` apart, without bursts.
+    pub fn with_rate_limit(mut self, per_second: u32) -> CompoundCache {
+        self.set_rate_limit(Some(per_second));
+        self
+    }
+    /// Sets the request rate limit, `None` meaning unlimited.
+    pub fn set_rate_limit(&mut self, per_second: Option<u32>) {
+        self.rate_limit = per_second.map(RateLimiter::new);
+    }
+    /// Requests per second allowed, `None` meaning unlimited.
+    pub fn rate_limit(&self) -> Option<u32> {
+        self.rate_limit.as_ref().map(RateLimiter::per_second)
+    }
+    /// Retries fetches that fail transiently (see `CacheError::is_transient`), making up to `max_attempts` attempts in total. The wait before each retry doubles, starting at `base_delay`. Failures caused by the request itself, like an unknown identifier, are returned right away. A retried fetch counts once against the fetch budget.
+    pub fn with_retry(mut self, max_attempts: u32, base_delay: Duration) -> CompoundCache {
+        self.set_retry(max_attempts, base_delay);
+        self
+    }
+    /// Sets how fetches are retried, see `with_retry`. One attempt turns retrying off.
+    pub fn set_retry(&mut self, max_attempts: u32, base_delay: Duration) {
+        self.retry = (max_attempts > 1).then_some(RetryPolicy { max_attempts, base_delay });
+    }
+    /// How long to wait before the next request to stay within the rate limit.
+    fn throttle(&mut self) -> Duration {
+        self.rate_limit.as_mut().map_or(Duration::ZERO, |limiter| limiter.take(Instant::now()))
+    }
+    /// Remembers failed fetches, so looking the compound up again fails with `CacheError::PreviouslyFailed` instead of making another request. Use `retry_failed` to try them again.
+    pub fn with_negative_caching(mut self) -> CompoundCache {
+        self.negative_caching = true;
+        self
+    }
+    /// Turns negative caching on or off. Turning it off forgets every failure.
+    pub fn set_negative_caching(&mut self, enabled: bool) {
+        self.negative_caching = enabled;
+        if !enabled {
+            self.failed.clear();
+        }
+    }
+    /// Keys whose last fetch failed while negative caching was on.
+    pub fn failed(&self) -> impl Iterator<Item = &SerCompound> {
+        self.failed.keys()
+    }
+    /// Treats fetches that populate fewer than `min` of the requested properties as degraded, which happens when PubChem can't compute them. Degraded properties are still stored, and their keys listed by `degraded`, unless `with_strict_properties` is set.
+    pub fn with_min_properties(mut self, min: usize) -> CompoundCache {
+        self.min_properties = Some(min);
+        self
+    }
+    /// Makes degraded fetches (see `with_min_properties`) fail with `CacheError::DegradedResponse` instead of being stored.
+    pub fn with_strict_properties(mut self) -> CompoundCache {
+        self.strict_properties = true;
+        self
+    }
+    /// Keys whose stored properties came from a degraded fetch (see `with_min_properties`).
+    pub fn degraded(&self) -> impl Iterator<Item = &SerCompound> {
+        self.degraded.iter()
+    }
+    /// Checks a fetch of the `requested` properties against `min_properties`, returning whether it was degraded.
+    fn check_degraded(&self, requested: &[CompoundProperty], props: &Properties) -> Result<bool, CacheError> {
+        let Some(min) = self.min_properties else {
+            return Ok(false);
+        };
+        let populated = populated_properties(props).filter(|property| requested.contains(property)).count();
+        if populated >= min {
+            Ok(false)
+        } else if self.strict_properties {
+            Err(CacheError::DegradedResponse(populated, requested.len()))
+        } else {
+            Ok(true)
+        }
+    }
+    fn spend_budget(&mut self) -> Result<(), CacheError> {
+        if let Some(budget) = &mut self.fetch_budget {
+            if *budget == 0 {
+                return Err(CacheError::BudgetExceeded);
+            }
+            *budget -= 1;
+        }
+        Ok(())
+    }
+    fn fetch(&mut self, cmp: &SerCompound) -> Result<FetchedProperties, CacheError> {
+        self.fetch_properties(cmp, self.properties_for(cmp.namespace))
+    }
+    /// The only place properties are fetched, so (with `PubChemFetcher`) the identifier is parsed at most once per operation.
+    fn fetch_properties(&mut self, cmp: &SerCompound, properties: &[CompoundProperty]) -> Result<FetchedProperties, CacheError> {
+        self.begin_fetch(cmp)?;
+        let mut attempt = 1;
+        let fetched = loop {
+            thread::sleep(self.throttle());
+            let start = Instant::now();
+            *self.stats.network_fetches.get_mut() += 1;
+            let fetched = self.fetcher.0.fetch(cmp, properties);
+            self.record_fetch_latency(start.elapsed());
+            match self.retry_delay(attempt, &fetched) {
+                Some(delay) => thread::sleep(delay),
+                None => break fetched
+            }
+            attempt += 1;
+        };
+        self.check_fetched(cmp, properties, fetched)
+    }
+    /// How long to back off before attempt `attempt + 1`, if `result` failed transiently and attempts are left (see `with_retry`).
+    fn retry_delay<T>(&self, attempt: u32, result: &Result<T, CacheError>) -> Option<Duration> {
+        let retry = self.retry?;
+        match result {
+            Err(e) if e.is_transient() && attempt < retry.max_attempts => Some(retry.base_delay.saturating_mul(1 << (attempt - 1).min(16))),
+            _ => None
+        }
+    }
+    /// The bookkeeping before a single fetch: refuses compounds that failed before, spends the budget and counts the fetch.
+    fn begin_fetch(&mut self, cmp: &SerCompound) -> Result<(), CacheError> {
+        if let Some(error) = self.failed.get(cmp) {
+            return Err(CacheError::PreviouslyFailed(error.clone()));
+        }
+        self.spend_budget()?;
+        *self.fetches_by_namespace.entry(cmp.namespace).or_insert(0) += 1;
+        Ok(())
+    }
+    /// Validates a fetcher's result for `cmp`, tracking degraded responses and remembering failures when negative caching is on.
+    fn check_fetched(&mut self, cmp: &SerCompound, properties: &[CompoundProperty], fetched: Result<FetchedProperties, CacheError>) -> Result<FetchedProperties, CacheError> {
+        let fetched = fetched.and_then(|fetched| {
+            check_cid(&fetched.props)?;
+            if self.check_degraded(properties, &fetched.props)? {
+                self.degraded.insert(cmp.clone());
+            } else {
+                self.degraded.remove(cmp);
+            }
+            Ok(fetched)
+        });
+        if let Err(e) = &fetched {
+            if self.negative_caching {
+                self.failed.insert(cmp.clone(), e.to_string());
+            }
+        }
+        fetched
+    }
+    fn record_fetch_latency(&mut self, latency: Duration) {
+        // Weight recent fetches more heavily, PubChem's response times drift
+        self.average_fetch_latency = Some(match self.average_fetch_latency {
+            Some(average) => average.mul_f64(0.8) + latency.mul_f64(0.2),
+            None => latency
+        });
+    }
+    /// How many fetches this cache made per namespace (`"cid"`, `"name"`, ...), failed ones included. Namespaces never fetched are left out.
+    pub fn fetches_by_namespace(&self) -> HashMap<String, u64> {
+        self.fetches_by_namespace.iter().map(|(ns, &count)| (ns.as_str().to_owned(), count)).collect()
+    }
+    /// Rolling average of observed fetch durations, `None` until something has been fetched.
+    pub fn average_fetch_latency(&self) -> Option<Duration> {
+        self.average_fetch_latency
+    }
+    /// Estimates how long fetching `misses` compounds would take, from the observed fetch latency and the rate limit (see `with_rate_limit`). Fetches run one at a time, so this scales linearly. Without a rate limit, zero until something has been fetched.
+    pub fn estimate_fetch_time(&self, misses: usize) -> Duration {
+        let latency = self.average_fetch_latency.unwrap_or_default();
+        let Some(later) = misses.checked_sub(1) else {
+            return Duration::ZERO;
+        };
+        // Each fetch after the first starts once the previous one finished and the limiter allows it
+        let spacing = self.rate_limit.as_ref().map_or(latency, |limiter| latency.max(limiter.interval()));
+        latency + spacing * later as u32
+    }
+    /// Inserts fetched properties under `cmp`, registering the compound's CID as an alias when it was fetched under another key. Returns the registered `(alias, primary)` pair.
+    ///
+    /// If `cmp` is a CID that PubChem redirected to another one (because the compounds were merged), the properties are stored under the current CID instead and `cmp` becomes its alias.
+    fn insert_fetched(&mut self, cmp: SerCompound, fetched: FetchedProperties) -> Option<(SerCompound, SerCompound)> {
+        let mut entry = CachedEntry {
+            source: Some(cmp.clone()),
+            fetched: self.properties_for(cmp.namespace).iter().copied().collect(),
+            ..CachedEntry::from(fetched)
+        };
+        self.strip_disallowed(&mut entry.props);
+        let mut registered = None;
+        let mut cmp = cmp;
+        if let Ok(cid) = u32::try_from(entry.props.cid) {
+            let current = self.normalize(SerCompound::new(cid));
+            if cmp.namespace == Namespace::Cid && current != cmp {
+                remove_entry(&mut self.cache, &cmp);
+                self.aliases.insert(cmp.clone(), current.clone());
+                registered = Some((cmp, current.clone()));
+                cmp = current;
+            } else if current != cmp && !self.cache.contains_key(&current) && !self.aliases.contains_key(&current) {
+                self.aliases.insert(current.clone(), cmp.clone());
+                registered = Some((current, cmp.clone()));
+            }
+        }
+        self.cache.insert(cmp, entry);
+        self.mark_dirty();
+        registered
+    }
+    /// Fetches and inserts the compound, returning `true`, unless it is already cached, in which case nothing happens and `false` is returned. Expired and partial entries are fetched again. Use overwrite for overwriting. If the compound namespaces are not the same, then the compound properties will be duplicated (see `deduplicate`), though lookups by the CID of a fetched compound resolve to the existing entry.
+    pub fn store(&mut self, cmp: SerCompound) -> Result<bool, CacheError> {
+        let cmp = self.normalize(cmp);
+        if self.lookup_normalized(&cmp).is_some() && !self.is_stale(&cmp) {
+            return Ok(false);
+        }
+        let key = self.resolve(&cmp).cloned().unwrap_or(cmp);
+        let props = self.fetch(&key)?;
+        self.insert_fetched(key, props);
+        Ok(true)
+    }
+    /// Overwrites properties, returning the ones previously stored under the key (like `HashMap::insert`). An alias is resolved first, so the entry it points to is the one refetched and replaced.
+    ///
+    /// If the fetched data matches the stored entry, only the entry's timestamp is updated and the cache isn't marked dirty.
+    pub fn overwrite(&mut self, cmp: SerCompound) -> Result<Option<Properties>, CacheError> {
+        let cmp = self.normalize(cmp);
+        let cmp = self.resolve(&cmp).cloned().unwrap_or(cmp);
+        let mut props = self.fetch(&cmp)?;
+        self.strip_disallowed(&mut props.props);
+        let fetched: HashSet<CompoundProperty> = self.properties_for(cmp.namespace).iter().copied().collect();
+        if let Some(entry) = self.cache.get_mut(&cmp) {
+            if SerProperties::from(&entry.props) == SerProperties::from(&props.props) && entry.annotations == props.annotations && entry.fetched == fetched {
+                entry.fetched_at = SystemTime::now();
+                return Ok(Some(propclone(&entry.props)));
+            }
+        }
+        let previous = remove_entry(&mut self.cache, &cmp).map(|entry| entry.props);
+        self.insert_fetched(cmp, props);
+        Ok(previous)
+    }
+    /// If the compound does not exist, the properties are fetched, added and returned. A cached compound is returned without a request, unless it expired (see `with_ttl`) or is partial (see `get_with`). The `bool` is whether the compound was cached.
+    pub fn get(&mut self, cmp: SerCompound) -> Result<(bool, &Properties), CacheError> {
+        let cmp = self.normalize(cmp);
+        let haskey = self.lookup_normalized(&cmp).is_some();
+        let hit = haskey && !self.is_stale(&cmp);
+        self.record_lookup(hit);
+        if !hit {
+            let key = self.resolve(&cmp).cloned().unwrap_or_else(|| cmp.clone());
+            let props = self.fetch(&key)?;
+            self.insert_fetched(key, props);
+        }
+        let props = self.access_normalized(&cmp).ok_or_else(|| CacheError::NotCached(cmp.clone()))?;
+        Ok((haskey, props))
+    }
+    /// Like `get`, for async code. A hit returns without fetching; a miss is fetched on tokio's blocking thread pool, so the calling task isn't stalled by the request. Requires the `async` feature and a tokio runtime.
+    #[cfg(feature = "async")]
+    pub async fn get_async(&mut self, cmp: SerCompound) -> Result<(bool, &Properties), CacheError> {
+        let cmp = self.normalize(cmp);
+        let haskey = self.lookup_normalized(&cmp).is_some();
+        let hit = haskey && !self.is_stale(&cmp);
+        self.record_lookup(hit);
+        if !hit {
+            let key = self.resolve(&cmp).cloned().unwrap_or_else(|| cmp.clone());
+            let properties = self.properties_for(key.namespace);
+            self.begin_fetch(&key)?;
+            let mut attempt = 1;
+            let mut backoff = Duration::ZERO;
+            let fetched = loop {
+                *self.stats.network_fetches.get_mut() += 1;
+                let fetcher = Arc::clone(&self.fetcher.0);
+                let task_key = key.clone();
+                let wait = backoff + self.throttle();
+                let start = Instant::now();
+                let fetched = match tokio::task::spawn_blocking(move || {
+                    thread::sleep(wait);
+                    fetcher.fetch(&task_key, properties)
+                }).await {
+                    Ok(fetched) => fetched,
+                    Err(e) => match e.try_into_panic() {
+                        Ok(panic) => std::panic::resume_unwind(panic),
+                        Err(e) => Err(CacheError::Io(io::Error::new(io::ErrorKind::Interrupted, e)))
+                    }
+                };
+                self.record_fetch_latency(start.elapsed().saturating_sub(wait));
+                match self.retry_delay(attempt, &fetched) {
+                    Some(delay) => backoff = delay,
+                    None => break fetched
+                }
+                attempt += 1;
+            };
+            let fetched = self.check_fetched(&key, properties, fetched)?;
+            self.insert_fetched(key, fetched);
+        }
+        let props = self.access_normalized(&cmp).ok_or_else(|| CacheError::NotCached(cmp.clone()))?;
+        Ok((haskey, props))
+    }
+    /// Like `get`, but also returns the steps taken to resolve the compound, for diagnosing surprising cache behavior.
+    pub fn get_traced(&mut self, cmp: SerCompound) -> Result<(Vec<ResolutionStep>, &Properties), CacheError> {
+        let cmp = self.normalize(cmp);
+        let mut steps = Vec::new();
+        let key = if self.cache.contains_key(&cmp) && !self.is_stale(&cmp) {
+            steps.push(ResolutionStep::CacheHit(cmp.clone()));
+            cmp
+        } else if let Some(primary) = self.resolve(&cmp).filter(|_| !self.is_stale(&cmp)).cloned() {
+            steps.push(ResolutionStep::Alias { alias: cmp, primary: primary.clone() });
+            primary
+        } else if self.baseline.as_ref().is_some_and(|baseline| baseline.lookup(&cmp).is_some()) {
+            steps.push(ResolutionStep::Baseline(cmp.clone()));
+            cmp
+        } else {
+            // An expired alias is fetched again under its primary, like in `get`
+            let key = self.resolve(&cmp).cloned().unwrap_or_else(|| cmp.clone());
+            let props = self.fetch(&key)?;
+            steps.push(ResolutionStep::Fetch(key.clone()));
+            if let Some((alias, primary)) = self.insert_fetched(key, props) {
+                steps.push(ResolutionStep::AliasRegistered { alias, primary });
+            }
+            cmp
+        };
+        let props = self.access_normalized(&key).ok_or(CacheError::NotCached(key))?;
+        Ok((steps, props))
+    }
+
+    /// Looks up several compounds, returning their properties in the same order. Cached compounds make no request. Uncached CIDs are fetched together with a single `PropertyFetcher::fetch_many` call, though each counts against the fetch budget; compounds in other namespaces are fetched one at a time, as PubChem only takes lists of CIDs.
+    ///
+    /// Everything that could be fetched is stored before the first error is returned.
+    pub fn get_many(&mut self, cmps: &[SerCompound]) -> Result<Vec<&Properties>, CacheError> {
+        let cmps: Vec<SerCompound> = cmps.iter().map(|cmp| self.normalize(cmp.clone())).collect();
+        let mut first_error = None;
+        let mut cids = Vec::new();
+        for cmp in &cmps {
+            let cached = self.lookup_normalized(cmp).is_some();
+            if cached && !self.is_stale(cmp) {
+                continue;
+            }
+            if !cached && cmp.namespace == Namespace::Cid {
+                if !cids.contains(cmp) {
+                    cids.push(cmp.clone());
+                }
+                continue;
+            }
+            let key = self.resolve(cmp).cloned().unwrap_or_else(|| cmp.clone());
+            match self.fetch(&key) {
+                Ok(fetched) => {
+                    self.insert_fetched(key, fetched);
+                }
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        for (_, result) in self.store_cids(cids) {
+            if let Err(e) = result {
+                first_error.get_or_insert(e);
+            }
+        }
+        if let Some(e) = first_error {
+            return Err(e);
+        }
+        let cache = &*self;
+        cmps.into_iter().map(|cmp| cache.access_normalized(&cmp).ok_or(CacheError::NotCached(cmp))).collect()
+    }
+
+    /// Fetches `cids` with a single `PropertyFetcher::fetch_many` call and stores the results. Each CID counts against the fetch budget; the ones past it aren't fetched and fail with `CacheError::BudgetExceeded`. Returns each CID with its result; ones that failed before aren't fetched again.
+    fn store_cids(&mut self, cids: Vec<SerCompound>) -> Vec<(SerCompound, Result<(), CacheError>)> {
+        let (failed, mut cids): (Vec<SerCompound>, Vec<SerCompound>) = cids.into_iter().partition(|cmp| self.failed.contains_key(cmp));
+        let mut results: Vec<(SerCompound, Result<(), CacheError>)> = failed.into_iter()
+            .map(|cmp| {
+                let error = CacheError::PreviouslyFailed(self.failed[&cmp].clone());
+                (cmp, Err(error))
+            })
+            .collect();
+        if let Some(budget) = &mut self.fetch_budget {
+            let over = cids.split_off(cids.len().min(*budget));
+            *budget -= cids.len();
+            results.extend(over.into_iter().map(|cmp| (cmp, Err(CacheError::BudgetExceeded))));
+        }
+        if cids.is_empty() {
+            return results;
+        }
+        let properties = self.properties_for(Namespace::Cid);
+        *self.fetches_by_namespace.entry(Namespace::Cid).or_insert(0) += 1;
+        thread::sleep(self.throttle());
+        let start = Instant::now();
+        *self.stats.network_fetches.get_mut() += 1;
+        let fetched = self.fetcher.0.fetch_many(&cids, properties);
+        // Recorded per compound, so `estimate_fetch_time` stays meaningful
+        self.record_fetch_latency(start.elapsed() / cids.len() as u32);
+        for (cmp, fetched) in cids.into_iter().zip(fetched) {
+            let result = match self.check_fetched(&cmp, properties, fetched) {
+                Ok(fetched) => {
+                    self.insert_fetched(cmp.clone(), fetched);
+                    Ok(())
+                }
+                Err(e) => Err(e)
+            };
+            results.push((cmp, result));
+        }
+        results
+    }
+
+    /// Like `get`, but only fetches `properties` on a miss, leaving the other fields `None`. Entries fetched this way are partial: a later `store` or `get` fetches them again in full, and `get_with` reuses them only if they have every property asked for and haven't expired.
+    pub fn get_with(&mut self, cmp: SerCompound, properties: &[CompoundProperty]) -> Result<&Properties, CacheError> {
+        let cmp = self.normalize(cmp);
+        let fetched = match self.resolve(&cmp) {
+            Some(key) if self.is_expired(&self.cache[key]) => None,
+            Some(key) => Some(&self.cache[key].fetched),
+            None if self.baseline.as_ref().is_some_and(|baseline| baseline.lookup(&cmp).is_some()) => {
+                return self.access_normalized(&cmp).ok_or(CacheError::NotCached(cmp));
+            }
+            None => None
+        };
+        if fetched.is_some_and(|fetched| properties.iter().all(|property| fetched.contains(property))) {
+            return self.access_normalized(&cmp).ok_or(CacheError::NotCached(cmp));
+        }
+        // Keep what the entry already had, as the refetched one replaces it
+        let mut requested: Vec<CompoundProperty> = match fetched {
+            Some(fetched) => ALL_PROPERTIES.iter().filter(|property| fetched.contains(property)).copied().collect(),
+            None => Vec::new()
+        };
+        for property in properties {
+            if !requested.contains(property) {
+                requested.push(*property);
+            }
+        }
+        let key = self.resolve(&cmp).cloned().unwrap_or_else(|| cmp.clone());
+        let fetched = self.fetch_properties(&key, &requested)?;
+        self.insert_fetched(key, fetched);
+        let key = self.resolve(&cmp).cloned().ok_or_else(|| CacheError::NotCached(cmp.clone()))?;
+        if let Some(entry) = self.cache.get_mut(&key) {
+            entry.fetched = requested.into_iter().collect();
+        }
+        self.access_normalized(&key).ok_or(CacheError::NotCached(key))
+    }
+    /// Whether the entry `cmp` resolves to has to be fetched again: it expired (see `with_ttl`), or it was fetched without some of the properties fetched for its namespace (see `get_with`). Inserted entries are never partial.
+    fn is_stale(&self, cmp: &SerCompound) -> bool {
+        self.resolve(cmp).is_some_and(|key| {
+            let entry = &self.cache[key];
+            self.is_expired(entry) || (entry.source.is_some() && !self.properties_for(key.namespace).iter().all(|property| entry.fetched.contains(property)))
+        })
+    }
+
+    /// The cached properties if `store` wouldn't fetch `cmp` again, i.e. it is cached and neither expired nor partial. Lets `SharedCompoundCache` serve hits under a read lock.
+    pub(crate) fn get_hit(&self, cmp: &SerCompound) -> Option<&Properties> {
+        let cmp = self.normalized(cmp);
+        if self.is_stale(cmp.as_ref()) {
+            return None;
+        }
+        self.access_normalized(cmp.as_ref())
+    }
+
+    /// The properties that were fetched for the entry `cmp` resolves to, telling a property PubChem had no value for (fetched, but `None`) from one that was never asked for. For inserted entries, the properties that have a value.
+    pub fn fetched_properties(&self, cmp: &SerCompound) -> Option<&HashSet<CompoundProperty>> {
+        let key = self.resolve(self.normalized(cmp).as_ref())?;
+        Some(&self.cache[key].fetched)
+    }
+
+    /// The key that triggered the fetch of the entry `cmp` resolves to, e.g. the name a compound now looked up by CID was originally requested by. `None` if `cmp` isn't cached or its entry was inserted rather than fetched.
+    pub fn source_of(&self, cmp: &SerCompound) -> Option<&SerCompound> {
+        let key = self.resolve(self.normalized(cmp).as_ref())?;
+        self.cache[key].source.as_ref()
+    }
+
+    /// Tries several identifiers for the same compound in order, returning the first that is cached or can be fetched. The remaining identifiers become aliases of it, so later lookups by any of them hit the cache.
+    ///
+    /// Fails with `CacheError::AllFailed` if none resolve.
+    pub fn get_any(&mut self, cmps: &[SerCompound]) -> Result<&Properties, CacheError> {
+        let mut errors = Vec::new();
+        let mut resolved = None;
+        for cmp in cmps {
+            let cmp = self.normalize(cmp.clone());
+            if self.lookup_normalized(&cmp).is_some() {
+                resolved = Some(cmp);
+                break;
+            }
+            match self.fetch(&cmp) {
+                Ok(props) => {
+                    self.insert_fetched(cmp.clone(), props);
+                    resolved = Some(cmp);
+                    break;
+                }
+                Err(e) => errors.push((cmp, e))
+            }
+        }
+        let resolved = resolved.ok_or(CacheError::AllFailed(errors))?;
+        // Baseline entries can't be aliased, as aliases only point into this layer
+        if let Some(primary) = self.resolve(&resolved).cloned() {
+            for cmp in cmps {
+                let cmp = self.normalize(cmp.clone());
+                if self.lookup_normalized(&cmp).is_none() {
+                    self.aliases.insert(cmp, primary.clone());
+                    self.mark_dirty();
+                }
+            }
+        }
+        self.access_normalized(&resolved).ok_or(CacheError::NotCached(resolved))
+    }
+
+    /// The compound's 2D structure depiction as PNG bytes. The compound is stored if needed, and the image is fetched once and cached alongside its properties.
+    pub fn get_image(&mut self, cmp: SerCompound) -> Result<&[u8], CacheError> {
+        let cmp = self.normalize(cmp);
+        if self.resolve(&cmp).is_none() {
+            let props = self.fetch(&cmp)?;
+            self.insert_fetched(cmp.clone(), props);
+        }
+        let key = self.resolve(&cmp).cloned().ok_or(CacheError::NotCached(cmp))?;
+        if self.cache[&key].image_png.is_none() {
+            self.spend_budget()?;
+            thread::sleep(self.throttle());
+            *self.stats.network_fetches.get_mut() += 1;
+            let image = self.fetcher.0.fetch_image(&key)?;
+            self.cache.get_mut(&key).ok_or_else(|| CacheError::NotCached(key.clone()))?.image_png = Some(image);
+            self.mark_dirty();
+        }
+        Ok(self.cache[&key].image_png.as_deref().unwrap_or_default())
+    }
+
+    /// Fetches every negatively cached key again (see `with_negative_caching`), storing the ones that now succeed. Keys that fail again stay negatively cached.
+    pub fn retry_failed(&mut self) -> Vec<(SerCompound, Result<(), CacheError>)> {
+        let failed: Vec<SerCompound> = self.failed.drain().map(|(cmp, _)| cmp).collect();
+        failed.into_iter().map(|cmp| {
+            let result = self.store(cmp.clone()).map(|_| ());
+            (cmp, result)
+        }).collect()
+    }
+
+    /// Stores each SMILES, returning the ones that failed alongside their errors. Stops with `CacheError::BudgetExceeded` once the fetch budget runs out, since every later SMILES would fail the same way.
+    pub fn store_smiles_list(&mut self, smiles: &[&str]) -> Result<Vec<(String, CacheError)>, CacheError> {
+        let mut errors = Vec::new();
+        for smiles in smiles {
+            match self.store(SerCompound::with_smiles(smiles)) {
+                Ok(_) => (),
+                Err(CacheError::BudgetExceeded) => return Err(CacheError::BudgetExceeded),
+                Err(e) => errors.push((smiles.to_string(), e))
+            }
+        }
+        Ok(errors)
+    }
+
+    /// Builds a cache from a list of SMILES (see `store_smiles_list`).
+    pub fn from_smiles_list(smiles: &[&str]) -> Result<(CompoundCache, Vec<(String, CacheError)>), CacheError> {
+        Self::from_smiles_list_with(PubChemFetcher, smiles)
+    }
+    /// Like `from_smiles_list`, fetching through `fetcher` (see `with_fetcher`).
+    pub fn from_smiles_list_with(fetcher: impl PropertyFetcher + 'static, smiles: &[&str]) -> Result<(CompoundCache, Vec<(String, CacheError)>), CacheError> {
+        let mut cache = CompoundCache::new().with_fetcher(fetcher);
+        let errors = cache.store_smiles_list(smiles)?;
+        Ok((cache, errors))
+    }
+
+    /// Caches the compound in a MOL or SDF file (its first record), looked up by the InChI or SMILES in the file's data items, e.g. `> <SMILES>`. The structure itself isn't parsed, so files without such an item fail with `CacheError::NoIdentifier`.
+    pub fn cache_from_molfile<P: AsRef<Path>>(&mut self, path: P) -> Result<&Properties, CacheError> {
+        let path = path.as_ref();
+        let (namespace, identifier) = sdf::structure_identifier(&fs::read_to_string(path)?)
+            .ok_or_else(|| CacheError::NoIdentifier(path.display().to_string()))?;
+        let cmp = SerCompound::with_nmsp_iden(namespace, &identifier);
+        self.store(cmp.clone())?;
+        self.get_ref(&cmp).ok_or(CacheError::NotCached(cmp))
+    }
+
+    /// Stores every `namespace,identifier` row of a CSV as it is read, calling `on_row` with each row's compound and result. A `namespace,identifier` header row is skipped.
+    ///
+    /// CID rows are collected into batches of `CSV_CID_BATCH` and fetched together (see `get_many`), so they are reported once their batch is done rather than in file order. A row that fails to store is reported through `on_row`; read errors and rows that can't be parsed abort the import, after the rows before them are stored.
+    pub fn cache_from_csv_reader(&mut self, r: impl Read, mut on_row: impl FnMut(&SerCompound, &Result<(), CacheError>)) -> Result<(), CacheError> {
+        let mut cids = Vec::new();
+        for (i, line) in BufReader::new(r).lines().enumerate() {
+            let cmp = match line.map_err(CacheError::from).and_then(|line| csv::parse_identifier_row(i, &line)) {
+                Ok(Some(cmp)) => cmp,
+                Ok(None) => continue,
+                Err(e) => {
+                    self.store_csv_cids(&mut cids, &mut on_row);
+                    return Err(e);
+                }
+            };
+            if cmp.namespace == Namespace::Cid {
+                cids.push(cmp);
+                if cids.len() == CSV_CID_BATCH {
+                    self.store_csv_cids(&mut cids, &mut on_row);
+                }
+            } else {
+                let result = self.store(cmp.clone()).map(|_| ());
+                on_row(&cmp, &result);
+            }
+        }
+        self.store_csv_cids(&mut cids, &mut on_row);
+        Ok(())
+    }
+    /// Stores and reports a batch of CID rows read by `cache_from_csv_reader`, fetching the uncached ones together.
+    fn store_csv_cids(&mut self, cids: &mut Vec<SerCompound>, on_row: &mut impl FnMut(&SerCompound, &Result<(), CacheError>)) {
+        let rows: Vec<(SerCompound, SerCompound)> = cids.drain(..).map(|cmp| (cmp.clone(), self.normalize(cmp))).collect();
+        let mut uncached = Vec::new();
+        for (_, key) in &rows {
+            if self.lookup_normalized(key).is_none() && !uncached.contains(key) {
+                uncached.push(key.clone());
+            }
+        }
+        let mut results: HashMap<SerCompound, Result<(), CacheError>> = self.store_cids(uncached).into_iter().collect();
+        for (cmp, key) in rows {
+            // Cached, expired or repeated rows take the usual path
+            let result = results.remove(&key).unwrap_or_else(|| self.store(key).map(|_| ()));
+            on_row(&cmp, &result);
+        }
+    }
+
+    /// Caches every row of a PubChem JSON `PropertyTable` response under its CID, returning how many were imported.
+    ///
+    /// PubChem's PascalCase keys are mapped with `pubchem_property_key`; unknown keys are ignored.
+    pub fn import_pubchem_property_table(&mut self, table: &Value) -> Result<usize, CacheError> {
+        let rows = table
+            .get("PropertyTable").and_then(|t| t.get("Properties")).and_then(Value::as_array)
+            .ok_or_else(|| CacheError::Format("`PropertyTable.Properties` could not be found!".to_owned()))?;
+        for row in rows {
+            let row = row.as_object().ok_or_else(|| CacheError::Format("Property row was not an object!".to_owned()))?;
+            let obj: Map<String, Value> = row.iter()
+                .filter_map(|(key, value)| Some((pubchem_property_key(key)?.to_owned(), value.clone())))
+                .collect();
+            let props = properties_from_object(&obj)?;
+            let cid = u32::try_from(props.cid).map_err(|_| CacheError::InvalidCid(props.cid.to_string()))?;
+            self.insert(SerCompound::new(cid), props)?;
+        }
+        Ok(rows.len())
+    }
+
+    /// Removes every entry whose namespace isn't `ns`, along with its aliases, returning how many entries were removed.
+    pub fn retain_namespace(&mut self, ns: Namespace) -> usize {
+        let before = self.cache.len();
+        self.retain(|cmp, _| cmp.namespace == ns);
+        before - self.cache.len()
+    }
+
+    /// The best label for a cached compound: its `title`, else its `iupac_name`, else the identifier it was looked up by. `None` if it isn't cached.
+    pub fn display_name(&self, cmp: &SerCompound) -> Option<String> {
+        let props = self.lookup(cmp)?;
+        Some(props.title.clone().or_else(|| props.iupac_name.clone()).unwrap_or_else(|| cmp.identifier.clone()))
+    }
+
+    /// Writes a GitHub-flavored Markdown table of every entry, sorted by key, with its display name (see `display_name`), formula, molecular weight, and XLogP. Missing values are left blank.
+    pub fn to_markdown(&self, mut w: impl Write) -> Result<(), CacheError> {
+        writeln!(w, "| Name | Formula | Molecular weight | XLogP |")?;
+        writeln!(w, "| --- | --- | --- | --- |")?;
+        let mut keys: Vec<&SerCompound> = self.cache.keys().collect();
+        keys.sort();
+        for cmp in keys {
+            let props = &self.cache[cmp].props;
+            let name = self.display_name(cmp).unwrap_or_default();
+            let cells = [
+                name,
+                props.molecular_formula.clone().unwrap_or_default(),
+                props.molecular_weight.clone().unwrap_or_default(),
+                props.xlogp.map(|xlogp| xlogp.to_string()).unwrap_or_default()
+            ];
+            let cells: Vec<String> = cells.iter().map(|cell| cell.replace('|', "\\|")).collect();
+            writeln!(w, "| {} |", cells.join(" | "))?;
+        }
+        Ok(())
+    }
+
+    /// A plain-text block of the compound's key properties as aligned `key: value` lines, e.g. for printing in a CLI. Missing values print as `-`. `None` if the compound isn't cached.
+    pub fn format_entry(&self, cmp: &SerCompound) -> Option<String> {
+        let props = self.lookup(cmp)?;
+        let lines = [
+            ("Title", props.title.clone()),
+            ("Formula", props.molecular_formula.clone()),
+            ("Molecular weight", props.molecular_weight.clone()),
+            ("Canonical SMILES", props.canonical_smiles.clone()),
+            ("InChI", props.inchi.clone()),
+            ("InChIKey", props.inchi_key.clone()),
+            ("XLogP", props.xlogp.map(|xlogp| xlogp.to_string()))
+        ];
+        let width = lines.iter().map(|(key, _)| key.len()).max().unwrap_or(0) + 1;
+        let mut block = String::new();
+        for (key, value) in lines {
+            block.push_str(&format!("{:width$} {}\n", format!("{key}:"), value.as_deref().unwrap_or("-")));
+        }
+        Some(block)
+    }
+
+    /// Writes the cache as CSV, for spreadsheets: a `namespace,identifier,cid,molecular_formula,...` header with every property, then one row per entry in key order. Missing properties are empty cells, and cells containing commas (as InChIs do) are quoted.
+    pub fn to_csv<W: Write>(&self, mut w: W) -> Result<(), CacheError> {
+        let header: Vec<&str> = ["namespace", "identifier", "cid"].into_iter().chain(ALL_PROPERTIES.iter().map(property_key)).collect();
+        writeln!(w, "{}", header.join(","))?;
+        let mut keys: Vec<&SerCompound> = self.cache.keys().collect();
+        keys.sort();
+        for cmp in keys {
+            let props = &self.cache[cmp].props;
+            let mut cells = vec![cmp.namespace.as_str().to_owned(), cmp.identifier.clone(), props.cid.to_string()];
+            cells.extend(ALL_PROPERTIES.iter().map(|property| match property_value(props, property) {
+                Value::Null => String::new(),
+                Value::String(value) => value,
+                value => value.to_string()
+            }));
+            let cells: Vec<Cow<str>> = cells.iter().map(|cell| csv::quote_field(cell)).collect();
+            writeln!(w, "{}", cells.join(","))?;
+        }
+        Ok(())
+    }
+
+    /// Keys only stored in `self` and keys only stored in `other`, both sorted. Only the primary keys are compared, not properties, aliases, or baselines.
+    pub fn key_diff<'a>(&'a self, other: &'a CompoundCache) -> (Vec<&'a SerCompound>, Vec<&'a SerCompound>) {
+        let mut only_self: Vec<&SerCompound> = self.cache.keys().filter(|cmp| !other.cache.contains_key(*cmp)).collect();
+        let mut only_other: Vec<&SerCompound> = other.cache.keys().filter(|cmp| !self.cache.contains_key(*cmp)).collect();
+        only_self.sort();
+        only_other.sort();
+        (only_self, only_other)
+    }
+
+    /// Collapses entries with the same `cid` into one, returning how many were removed. The smallest key is kept (the `cid` one if there is one); the others become its aliases, so lookups by them still hit. Entries with a non-positive `cid` are left alone.
+    pub fn deduplicate(&mut self) -> usize {
+        let mut primaries: HashMap<i32, SerCompound> = HashMap::new();
+        for (cmp, entry) in self.cache.iter().filter(|(_, entry)| entry.props.cid > 0) {
+            let primary = primaries.entry(entry.props.cid).or_insert_with(|| cmp.clone());
+            if cmp < primary {
+                *primary = cmp.clone();
+            }
+        }
+        let duplicates: Vec<(SerCompound, SerCompound)> = self.cache.iter()
+            .filter_map(|(cmp, entry)| primaries.get(&entry.props.cid).filter(|primary| *primary != cmp).map(|primary| (cmp.clone(), primary.clone())))
+            .collect();
+        if duplicates.is_empty() {
+            return 0;
+        }
+        for (duplicate, primary) in &duplicates {
+            remove_entry(&mut self.cache, duplicate);
+            self.degraded.remove(duplicate);
+            self.aliases.insert(duplicate.clone(), primary.clone());
+        }
+        let moved: HashMap<&SerCompound, &SerCompound> = duplicates.iter().map(|(duplicate, primary)| (duplicate, primary)).collect();
+        for target in self.aliases.values_mut() {
+            if let Some(primary) = moved.get(target) {
+                *target = (*primary).clone();
+            }
+        }
+        self.mark_dirty();
+        duplicates.len()
+    }
+
+    /// The entry whose properties have the given `cid`, whatever key it was stored under (e.g. water cached by SMILES `O` is found by 962). If several match, the smallest key is returned, which is the `cid` one if there is one. Aliases and the baseline are not searched.
+    pub fn find_by_cid(&self, cid: i32) -> Option<(&SerCompound, &Properties)> {
+        self.cache.iter()
+            .filter(|(_, entry)| entry.props.cid == cid)
+            .min_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(cmp, entry)| (cmp, &entry.props))
+    }
+
+    /// Keys of every entry whose `molecular_formula` contains the element `symbol` (e.g. `"F"`), in sorted order. Entries without a parseable formula are skipped.
+    pub fn find_containing_element(&self, symbol: &str) -> Vec<&SerCompound> {
+        let mut found: Vec<&SerCompound> = self.cache.iter()
+            .filter(|(_, entry)| element_counts(&entry.props).is_some_and(|counts| counts.iter().any(|(element, _)| element == symbol)))
+            .map(|(cmp, _)| cmp)
+            .collect();
+        found.sort();
+        found
+    }
+
+    /// Sums the exact mass of `(compound, count)` pairs and lists which compounds carry isotope labels. Every compound must already be cached.
+    pub fn exact_mass_report(&self, items: &[(SerCompound, f64)]) -> Result<MassReport, CacheError> {
+        let mut report = MassReport::default();
+        for (cmp, count) in items {
+            let props = self.lookup(cmp).ok_or_else(|| CacheError::NotCached(cmp.clone()))?;
+            let exact_mass = props.exact_mass.as_ref().ok_or(CacheError::MissingField("exact_mass"))?;
+            let exact_mass = exact_mass.parse::<f64>().map_err(|_| CacheError::Format(format!("`exact_mass` ({exact_mass}) is not a number!")))?;
+            report.exact_mass += exact_mass * count;
+            if props.isotope_atom_count.unwrap_or(0) > 0 {
+                report.isotope_labeled.push(cmp.clone());
+            }
+        }
+        Ok(report)
+    }
+
+    /// Partitions compounds into fresh cache hits, stale entries and misses that would be fetched, and invalid compounds (unparseable CID). Does not make a PubChem request.
+    pub fn plan(&self, cmps: &[SerCompound]) -> FetchPlan {
+        let mut plan = FetchPlan::default();
+        for cmp in cmps {
+            if self.contains(cmp) {
+                if self.is_stale(self.normalized(cmp).as_ref()) {
+                    plan.stale.push(cmp.clone());
+                } else {
+                    plan.hits.push(cmp.clone());
+                }
+            } else if cmp.to_compound().is_ok() {
+                plan.misses.push(cmp.clone());
+            } else {
+                plan.invalid.push(cmp.clone());
+            }
+        }
+        plan
+    }
+
+    /// Picks up to `n` keys at random. The same seed and cache contents always give the same sample.
+    pub fn sample(&self, n: usize, seed: u64) -> Vec<&SerCompound> {
+        // HashMap order differs between instances, so shuffle from a sorted order
+        let mut keys: Vec<&SerCompound> = self.cache.keys().collect();
+        keys.sort();
+        let mut state = seed;
+        let n = n.min(keys.len());
+        for i in 0..n {
+            let j = i + (splitmix64(&mut state) % (keys.len() - i) as u64) as usize;
+            keys.swap(i, j);
+        }
+        keys.truncate(n);
+        keys
+    }
+
+    /// Maps every key and alias, written as `namespace:identifier`, to its CID, e.g. `{"name:water": 962}`. Leaves out all other property data.
+    pub fn to_identity_map(&self) -> Value {
+        let mut map = Map::new();
+        for (cmp, entry) in &self.cache {
+            map.insert(format!("{}:{}", cmp.namespace, cmp.identifier), entry.props.cid.into());
+        }
+        for (alias, primary) in &self.aliases {
+            if let Some(entry) = self.cache.get(primary) {
+                map.insert(format!("{}:{}", alias.namespace, alias.identifier), entry.props.cid.into());
+            }
+        }
+        Value::Object(map)
+    }
+
+    /// Entry and alias counts, namespace breakdown, completeness, estimated size, and entry ages in one go. The baseline is not included.
+    pub fn summarize(&self) -> CacheSummary {
+        let mut by_namespace = BTreeMap::new();
+        for cmp in self.cache.keys() {
+            *by_namespace.entry(cmp.namespace).or_insert(0) += 1;
+        }
+        let populated: usize = self.cache.values().map(|entry| populated_properties(&entry.props).count()).sum();
+        let completeness = if self.cache.is_empty() {
+            0.0
+        } else {
+            populated as f64 / (self.cache.len() * ALL_PROPERTIES.len()) as f64
+        };
+        let ages = self.cache.values().map(|entry| entry.fetched_at.elapsed().unwrap_or_default());
+        CacheSummary {
+            entries: self.cache.len(),
+            aliases: self.aliases.len(),
+            by_namespace,
+            completeness,
+            estimated_bytes: self.estimated_json_bytes(),
+            oldest: ages.clone().max(),
+            newest: ages.min()
+        }
+    }
+
+    /// Size in bytes of the entry `cmp` resolves to, as written by `serialize`, e.g. for finding the entries that dominate the file size. Aliases are not counted.
+    pub fn entry_json_bytes(&self, cmp: &SerCompound) -> Option<usize> {
+        let key = self.resolve(self.normalized(cmp).as_ref())?;
+        serde_json::to_string(&SerEntry::new(key, &self.cache[key])).ok().map(|json| json.len())
+    }
+
+    /// Roughly how many bytes `serialize().to_string()` would produce, without serializing. Numbers are assumed to take `ESTIMATED_NUMBER_BYTES` each and strings are assumed to need no escaping.
+    pub fn estimated_json_bytes(&self) -> usize {
+        let key_overhead: usize = ALL_PROPERTIES.iter().map(|property| property_key(property).len() + 4).sum();
+        let entries: usize = self.cache.iter().map(|(cmp, entry)| {
+            let prop = &entry.props;
+            let string_fields = [
+                &prop.canonical_smiles, &prop.exact_mass, &prop.fingerprint_2d, &prop.inchi, &prop.inchi_key, &prop.isomeric_smiles,
+                &prop.iupac_name, &prop.molecular_formula, &prop.molecular_weight, &prop.monoisotopic_mass, &prop.title
+            ];
+            let strings: usize = string_fields.iter().map(|field| field.as_ref().map_or("null".len(), |s| s.len() + 2)).sum();
+            let numbers = (ALL_PROPERTIES.len() - string_fields.len()) * ESTIMATED_NUMBER_BYTES;
+            let annotations: usize = entry.annotations.iter().map(|(property, note)| property_key(property).len() + note.len() + 6).sum();
+            let image = entry.image_png.as_ref().map_or(0, |image| image.len().div_ceil(3) * 4 + "\"image_png\":\"\",".len());
+            let source = entry.source.as_ref().map_or(0, |source| source.namespace.as_str().len() + source.identifier.len() + 42);
+            let key = cmp.namespace.as_str().len() + cmp.identifier.len() + 32;
+            key + "\"properties\":{}".len() + key_overhead + strings + numbers + annotations + image + source + "{},".len()
+        }).sum();
+        let aliases: usize = self.aliases.iter().map(|(alias, primary)| {
+            alias.namespace.as_str().len() + alias.identifier.len() + primary.namespace.as_str().len() + primary.identifier.len() + 80
+        }).sum();
+        "{\"version\":1,\"generated_at\":\"1970-01-01T00:00:00Z\",\"cache\":[]}".len() + entries + aliases
+    }
+
+    /// Encodes the cache as MessagePack (see `MsgPackFormat`).
+    #[cfg(feature = "rmp")]
+    pub fn to_msgpack(&self) -> Result<Vec<u8>, CacheError> {
+        MsgPackFormat.serialize(self)
+    }
+
+    /// Decodes a cache written by `to_msgpack`.
+    #[cfg(feature = "rmp")]
+    pub fn from_msgpack(bytes: &[u8]) -> Result<CompoundCache, CacheError> {
+        MsgPackFormat.deserialize(bytes)
+    }
+
+    /// Encodes the cache with bincode (see `BincodeFormat`), much smaller than the JSON.
+    #[cfg(feature = "bincode")]
+    pub fn serialize_binary(&self) -> Result<Vec<u8>, CacheError> {
+        binary::encode(self)
+    }
+
+    /// Decodes a cache written by `serialize_binary`.
+    #[cfg(feature = "bincode")]
+    pub fn deserialize_binary(bytes: &[u8]) -> Result<CompoundCache, CacheError> {
+        binary::decode(bytes)
+    }
+
+    /// Serializes and deserializes the cache, checking that every entry, annotation, and alias comes back unchanged. The error describes the first divergence found.
+    pub fn validate_roundtrip(&self) -> Result<(), CacheError> {
+        let text = self.serialize()?.to_string();
+        let reloaded = CompoundCache::deserialize(text)?;
+        for (cmp, entry) in &self.cache {
+            let other = reloaded.cache.get(cmp)
+                .ok_or_else(|| CacheError::Format(format!("{}:{} was lost in the round trip!", cmp.namespace, cmp.identifier)))?;
+            if entry.props.cid != other.props.cid {
+                return Err(CacheError::Format(format!("`cid` of {}:{} changed from {} to {}!", cmp.namespace, cmp.identifier, entry.props.cid, other.props.cid)));
+            }
+            for property in ALL_PROPERTIES {
+                if let Some(value) = float_value(&entry.props, property).filter(|value| !value.is_finite()) {
+                    return Err(CacheError::Format(format!("`{}` of {}:{} is {value}, which JSON can't represent!", property_key(property), cmp.namespace, cmp.identifier)));
+                }
+                let (before, after) = (property_value(&entry.props, property), property_value(&other.props, property));
+                if before != after {
+                    return Err(CacheError::Format(format!("`{}` of {}:{} changed from {before} to {after}!", property_key(property), cmp.namespace, cmp.identifier)));
+                }
+            }
+            if entry.annotations != other.annotations {
+                return Err(CacheError::Format(format!("The annotations of {}:{} changed!", cmp.namespace, cmp.identifier)));
+            }
+            if entry.image_png != other.image_png {
+                return Err(CacheError::Format(format!("The image of {}:{} changed!", cmp.namespace, cmp.identifier)));
+            }
+            if entry.source != other.source {
+                return Err(CacheError::Format(format!("The source of {}:{} changed!", cmp.namespace, cmp.identifier)));
+            }
+            if entry.access_count.load(Ordering::Relaxed) != other.access_count.load(Ordering::Relaxed) {
+                return Err(CacheError::Format(format!("The access count of {}:{} changed!", cmp.namespace, cmp.identifier)));
+            }
+            if epoch_secs(entry.fetched_at) != epoch_secs(other.fetched_at) {
+                return Err(CacheError::Format(format!("The fetch time of {}:{} changed!", cmp.namespace, cmp.identifier)));
+            }
+            if entry.fetched != other.fetched {
+                return Err(CacheError::Format(format!("The fetched properties of {}:{} changed!", cmp.namespace, cmp.identifier)));
+            }
+        }
+        if reloaded.cache.len() != self.cache.len() {
+            return Err(CacheError::Format(format!("The round trip produced {} entries instead of {}!", reloaded.cache.len(), self.cache.len())));
+        }
+        if reloaded.aliases != self.aliases {
+            return Err(CacheError::Format("The aliases changed in the round trip!".to_owned()));
+        }
+        Ok(())
+    }
+
+    pub fn serialize(&self) -> Result<Value, CacheError> {
+        Ok(serde_json::to_value(self)?)
+    }
+
+    /// Like `serialize`, but renames property keys per `aliases` (e.g. `molecular_weight` to `mw`) for tools that expect other names. Unmapped keys are unchanged. The output can't be read back with `deserialize`.
+    pub fn serialize_with_aliases(&self, aliases: &HashMap<&str, &str>) -> Result<Value, CacheError> {
+        let mut root = self.serialize()?;
+        if let Some(Value::Array(entries)) = root.get_mut("cache") {
+            for entry in entries {
+                if let Some(Value::Object(props)) = entry.get_mut("properties") {
+                    *props = std::mem::take(props).into_iter()
+                        .map(|(key, value)| (aliases.get(key.as_str()).map_or(key, |&alias| alias.to_owned()), value))
+                        .collect();
+                }
+            }
+        }
+        Ok(root)
+    }
+
+    pub fn deserialize(st: String) -> Result<CompoundCache, CacheError> {
+        Self::deserialize_with_options(st, &DeserializeOptions::default())
+    }
+
+    pub fn deserialize_with_options(st: String, options: &DeserializeOptions) -> Result<CompoundCache, CacheError> {
+        Self::from_value(&serde_json::from_str::<Value>(&st)?, options)
+    }
+
+    fn from_value(root: &Value, options: &DeserializeOptions) -> Result<CompoundCache, CacheError> {
+        let version = format_version(root);
+        if version > CACHE_FORMAT_VERSION {
+            return Err(CacheError::UnsupportedVersion(version));
+        }
+        SerCache::deserialize(root).map_err(|e| CacheError::Format(e.to_string()))?.into_cache(options)
+    }
+
+    /// Loads a cache file, migrating it in place if it was written by an older format version. A missing file gives an empty cache.
+    ///
+    /// With the `gzip` feature, paths ending in `.gz` are read with `load_from_path_gzip`.
+    pub fn load_from_path<P: AsRef<Path>>(path: P) -> Result<CompoundCache, CacheError> {
+        Self::load(path.as_ref(), false)
+    }
+    /// `locked` tells whether the caller holds the exclusive lock on `path` (see `load_from_path_locked`), so writing a migrated file back doesn't take it again.
+    fn load(path: &Path, locked: bool) -> Result<CompoundCache, CacheError> {
+        #[cfg(feature = "gzip")]
+        if is_gzip_path(path) {
+            return Self::load_gzip(path, locked);
+        }
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(CompoundCache::new()),
+            Err(e) => return Err(e.into())
+        };
+        let mut root = serde_json::from_str::<Value>(&text)?;
+        let version = format_version(&root);
+        if version < CACHE_FORMAT_VERSION {
+            root = migrate(root, version)?;
+            write_back(path, root.to_string().as_bytes(), locked)?;
+        }
+        let cache = Self::from_value(&root, &DeserializeOptions::default())?;
+        cache.mark_saved(Some(path));
+        Ok(cache)
+    }
+
+    /// Like `load_from_path`, for a gzip-compressed cache file. The file is decompressed as it is parsed, never held in memory whole.
+    #[cfg(feature = "gzip")]
+    pub fn load_from_path_gzip<P: AsRef<Path>>(path: P) -> Result<CompoundCache, CacheError> {
+        Self::load_gzip(path.as_ref(), false)
+    }
+    #[cfg(feature = "gzip")]
+    fn load_gzip(path: &Path, locked: bool) -> Result<CompoundCache, CacheError> {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(CompoundCache::new()),
+            Err(e) => return Err(e.into())
+        };
+        let mut root: Value = serde_json::from_reader(BufReader::new(flate2::read::GzDecoder::new(file)))?;
+        let version = format_version(&root);
+        if version < CACHE_FORMAT_VERSION {
+            root = migrate(root, version)?;
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(root.to_string().as_bytes())?;
+            write_back(path, &encoder.finish()?, locked)?;
+        }
+        let cache = Self::from_value(&root, &DeserializeOptions::default())?;
+        cache.mark_saved(Some(path));
+        Ok(cache)
+    }
+
+    /// Like `load_from_path`, but first waits for any save in progress in another process to finish (see `save_to_path`). The lock is exclusive, as a file in an older format is written back.
+    #[cfg(feature = "fs-lock")]
+    pub fn load_from_path_locked<P: AsRef<Path>>(path: P) -> Result<CompoundCache, CacheError> {
+        let _lock = lock_file(path.as_ref(), true)?;
+        Self::load(path.as_ref(), true)
+    }
+
+    /// Writes the cache to `path`. Does nothing if `path` is the file the cache was last loaded from or saved to and nothing changed since (see `is_dirty`).
+    ///
+    /// The file is written next to `path` first and then renamed over it, so an interrupted save leaves the old file intact. With the `fs-lock` feature, an advisory lock on `<path>.lock` is held while writing, so saves from several processes wait for each other instead of interleaving.
+    ///
+    /// With the `gzip` feature, paths ending in `.gz` are written with `save_to_path_gzip`.
+    pub fn save_to_path<P: AsRef<Path>>(&self, path: P) -> Result<(), CacheError> {
+        #[cfg(feature = "gzip")]
+        if is_gzip_path(path.as_ref()) {
+            return self.save_to_path_gzip(path);
+        }
+        if self.is_saved_at(path.as_ref()) {
+            return Ok(());
+        }
+        write_locked(path.as_ref(), self.serialize()?.to_string().as_bytes())?;
+        self.mark_saved(Some(path.as_ref()));
+        Ok(())
+    }
+
+    /// Like `save_to_path`, but compresses the JSON with gzip.
+    #[cfg(feature = "gzip")]
+    pub fn save_to_path_gzip<P: AsRef<Path>>(&self, path: P) -> Result<(), CacheError> {
+        if self.is_saved_at(path.as_ref()) {
+            return Ok(());
+        }
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        serde_json::to_writer(&mut encoder, self)?;
+        write_locked(path.as_ref(), &encoder.finish()?)?;
+        self.mark_saved(Some(path.as_ref()));
+        Ok(())
+    }
+
+    /// Splits the cache across `shards` files (`shard_0.json`, `shard_1.json`, ...) in `dir`, partitioned by a hash of the key. The aliases are kept in `shard_0.json`. Each shard is replaced atomically, and shard files left over from a previous save with more shards are removed once every shard is written.
+    pub fn save_sharded<P: AsRef<Path>>(&self, dir: P, shards: usize) -> Result<(), CacheError> {
+        if shards == 0 {
+            return Err(CacheError::Format("The shard count must be at least 1!".to_owned()));
+        }
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        // One lock for the whole set, so concurrent saves don't interleave shards
+        #[cfg(feature = "fs-lock")]
+        let _lock = lock_file(&dir.join("shards"), true)?;
+        let mut buckets: Vec<Vec<(&SerCompound, &CachedEntry)>> = (0..shards).map(|_| Vec::new()).collect();
+        for (cmp, entry) in &self.cache {
+            let mut hasher = DefaultHasher::new();
+            cmp.hash(&mut hasher);
+            buckets[(hasher.finish() % shards as u64) as usize].push((cmp, entry));
+        }
+        let mut written = Vec::with_capacity(shards);
+        for (i, bucket) in buckets.into_iter().enumerate() {
+            let mut file = SerCache::new(bucket);
+            if i == 0 {
+                file.aliases = self.aliases.iter().map(|(alias, primary)| SerAlias { alias: alias.clone(), primary: primary.clone() }).collect();
+            }
+            let path = dir.join(format!("shard_{i}.json"));
+            write_atomic(&path, serde_json::to_string(&file)?.as_bytes())?;
+            written.push(path);
+        }
+        for path in shard_paths(dir)? {
+            if !written.contains(&path) {
+                fs::remove_file(path)?;
+            }
+        }
+        self.mark_saved(None);
+        Ok(())
+    }
+
+    /// Loads and merges every shard file written by `save_sharded` in `dir`, aliases included.
+    pub fn load_sharded<P: AsRef<Path>>(dir: P) -> Result<CompoundCache, CacheError> {
+        let mut output_cache = CompoundCache::new();
+        for path in shard_paths(dir.as_ref())? {
+            let shard = CompoundCache::load_from_path(path)?;
+            output_cache.cache.extend(shard.cache);
+            output_cache.aliases.extend(shard.aliases);
+            output_cache.generated_at = output_cache.generated_at.into_iter().chain(shard.generated_at).min();
+        }
+        Ok(output_cache)
+    }
+
+    /// Merges a cache file read from `r`, inserting each entry as soon as it is parsed rather than loading the whole file first. Entries already in the cache are kept if `keep_existing`, and replaced otherwise. Keys go through the key normalizer and properties through the allow-list, as with `insert`, and aliases whose primary isn't in the cache afterwards are dropped, as with `merge`. Returns how many entries were inserted.
+    ///
+    /// If reading fails partway, the entries merged so far stay in the cache, which is marked dirty.
+    pub fn merge_from_reader(&mut self, r: impl Read, keep_existing: bool) -> Result<usize, CacheError> {
+        let mut merge = MergeFile { cache: self, keep_existing, added: 0, aliases: Vec::new(), error: None };
+        let mut deserializer = serde_json::Deserializer::from_reader(r);
+        let result = de::DeserializeSeed::deserialize(&mut merge, &mut deserializer).and_then(|()| deserializer.end());
+        if let Some(e) = merge.error {
+            return Err(e);
+        }
+        result?;
+        Ok(merge.added)
+    }
+}
+
+/// The on-disk layout of a cache, `{"version", "generated_at", "cache": [...], "aliases": [...]}`.
+#[derive(Serialize, Deserialize)]
+struct SerCache {
+    /// Files without a version are version 0.
+    #[serde(default)]
+    version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    generated_at: Option<String>,
+    cache: Vec<SerEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    aliases: Vec<SerAlias>
+}
+impl SerCache {
+    fn new<'a>(entries: impl IntoIterator<Item = (&'a SerCompound, &'a CachedEntry)>) -> SerCache {
+        SerCache {
+            version: CACHE_FORMAT_VERSION,
+            generated_at: Some(timestamp::format_iso8601(SystemTime::now())),
+            cache: entries.into_iter().map(|(cmp, entry)| SerEntry::new(cmp, entry)).collect(),
+            aliases: Vec::new()
+        }
+    }
+    fn into_cache(self, options: &DeserializeOptions) -> Result<CompoundCache, CacheError> {
+        let mut output_cache = CompoundCache::new();
+        // Primaries moved to their CID, so aliases pointing at them can follow
+        let mut rekeyed = HashMap::new();
+        for entry in self.cache {
+            let (key, cached) = entry.into_parts()?;
+            match u32::try_from(cached.props.cid) {
+                Ok(cid) if options.canonicalize_to_cid && key.namespace != Namespace::Cid => {
+                    let primary = SerCompound::new(cid);
+                    output_cache.cache.entry(primary.clone()).or_insert(cached);
+                    output_cache.aliases.insert(key.clone(), primary.clone());
+                    rekeyed.insert(key, primary);
+                }
+                _ => {
+                    output_cache.cache.insert(key, cached);
+                }
+            }
+        }
+        output_cache.generated_at = self.generated_at.as_deref().and_then(timestamp::parse_iso8601);
+        for SerAlias { alias, primary } in self.aliases {
+            if output_cache.cache.contains_key(&alias) {
+                continue;
+            }
+            let primary = rekeyed.get(&primary).cloned().unwrap_or(primary);
+            output_cache.aliases.insert(alias, primary);
+        }
+        Ok(output_cache)
+    }
+}
+
+/// One element of a cache file's `cache` array.
+#[derive(Serialize, Deserialize)]
+struct SerEntry {
+    #[serde(flatten)]
+    key: SerCompound,
+    properties: SerProperties,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: BTreeMap<String, String>,
+    /// Base64-encoded PNG.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    image_png: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<SerCompound>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    access_count: u64,
+    /// When the entry was stored or last touched, in seconds since the Unix epoch. Entries without it are treated as fetched when loaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fetched_at: Option<u64>,
+    /// Keys of the properties that were fetched. Left out when all of them were.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fetched: Option<Vec<String>>
+}
+impl SerEntry {
+    fn new(cmp: &SerCompound, entry: &CachedEntry) -> SerEntry {
+        SerEntry {
+            key: cmp.clone(),
+            properties: SerProperties::from(&entry.props),
+            annotations: entry.annotations.iter().map(|(property, note)| (property_key(property).to_owned(), note.clone())).collect(),
+            image_png: entry.image_png.as_deref().map(base64::encode),
+            source: entry.source.clone(),
+            access_count: entry.access_count.load(Ordering::Relaxed),
+            fetched_at: epoch_secs(entry.fetched_at),
+            fetched: (entry.fetched.len() < ALL_PROPERTIES.len()).then(|| {
+                ALL_PROPERTIES.iter().filter(|property| entry.fetched.contains(property)).map(|property| property_key(property).to_owned()).collect()
+            })
+        }
+    }
+    fn into_parts(self) -> Result<(SerCompound, CachedEntry), CacheError> {
+        let mut cached = CachedEntry::new(self.properties.into());
+        for (field, note) in self.annotations {
+            let property = property_from_key(&field).ok_or_else(|| CacheError::Format(format!("Unknown annotated property `{field}`!")))?;
+            cached.annotations.insert(property, note);
+        }
+        if let Some(image) = self.image_png {
+            let image = base64::decode(&image).ok_or_else(|| CacheError::Format("`image_png` was not a base64 string!".to_owned()))?;
+            cached.image_png = Some(image);
+        }
+        cached.source = self.source;
+        cached.access_count = AtomicU64::new(self.access_count);
+        if let Some(secs) = self.fetched_at {
+            cached.fetched_at = UNIX_EPOCH + Duration::from_secs(secs);
+        }
+        cached.fetched = match self.fetched {
+            Some(fetched) => fetched.iter()
+                .map(|field| property_from_key(field).ok_or_else(|| CacheError::Format(format!("Unknown fetched property `{field}`!"))))
+                .collect::<Result<_, _>>()?,
+            None => ALL_PROPERTIES.iter().copied().collect()
+        };
+        Ok((self.key, cached))
+    }
+}
+
+/// One element of a cache file's `aliases` array: the alias key, plus the key it points to under `primary`.
+#[derive(Serialize, Deserialize)]
+struct SerAlias {
+    #[serde(flatten)]
+    alias: SerCompound,
+    primary: SerCompound
+}
+
+/// Streams a cache file into an existing cache for `merge_from_reader`. Errors that aren't JSON errors are kept in `error`.
+struct MergeFile<'a> {
+    cache: &'a mut CompoundCache,
+    keep_existing: bool,
+    added: usize,
+    /// Added once every entry is in, as the file may list them before the entries.
+    aliases: Vec<SerAlias>,
+    error: Option<CacheError>
+}
+impl MergeFile<'_> {
+    fn fail<E: de::Error>(&mut self, error: CacheError) -> E {
+        let message = error.to_string();
+        self.error = Some(error);
+        E::custom(message)
+    }
+}
+impl<'de> de::DeserializeSeed<'de> for &mut MergeFile<'_> {
+    type Value = ();
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+impl<'de> de::Visitor<'de> for &mut MergeFile<'_> {
+    type Value = ();
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a cache file")
+    }
+    fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(field) = map.next_key::<String>()? {
+            match field.as_str() {
+                "version" => {
+                    let version = map.next_value::<u32>()?;
+                    if version > CACHE_FORMAT_VERSION {
+                        return Err(self.fail(CacheError::UnsupportedVersion(version)));
+                    }
+                }
+                "cache" => map.next_value_seed(MergeEntries(&mut *self))?,
+                "aliases" => self.aliases.extend(map.next_value::<Vec<SerAlias>>()?),
+                _ => {
+                    map.next_value::<de::IgnoredAny>()?;
+                }
+            }
+        }
+        for SerAlias { alias, primary } in std::mem::take(&mut self.aliases) {
+            let (alias, primary) = (self.cache.normalize(alias), self.cache.normalize(primary));
+            let cache = &self.cache.cache;
+            if !cache.contains_key(&primary) || cache.contains_key(&alias) || (self.keep_existing && self.cache.aliases.contains_key(&alias)) {
+                continue;
+            }
+            self.cache.aliases.insert(alias, primary);
+            self.cache.mark_dirty();
+        }
+        Ok(())
+    }
+}
+
+/// The `cache` array of a file being merged, visited one entry at a time.
+struct MergeEntries<'a, 'b>(&'a mut MergeFile<'b>);
+impl<'de> de::DeserializeSeed<'de> for MergeEntries<'_, '_> {
+    type Value = ();
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+impl<'de> de::Visitor<'de> for MergeEntries<'_, '_> {
+    type Value = ();
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of cache entries")
+    }
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let merge = self.0;
+        while let Some(entry) = seq.next_element::<SerEntry>()? {
+            let (key, cached) = entry.into_parts().map_err(|e| merge.fail(e))?;
+            let (key, cached) = merge.cache.admit(key, cached);
+            if merge.keep_existing && merge.cache.cache.contains_key(&key) {
+                continue;
+            }
+            merge.cache.aliases.remove(&key);
+            merge.cache.cache.insert(key, cached);
+            merge.cache.mark_dirty();
+            merge.added += 1;
+        }
+        Ok(())
+    }
+}
+
+fn epoch_secs(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|since| since.as_secs())
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+impl Serialize for CompoundCache {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut file = SerCache::new(&self.cache);
+        file.aliases = self.aliases.iter().map(|(alias, primary)| SerAlias { alias: alias.clone(), primary: primary.clone() }).collect();
+        file.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CompoundCache {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let file = SerCache::deserialize(deserializer)?;
+        if file.version > CACHE_FORMAT_VERSION {
+            return Err(de::Error::custom(format!("Unsupported cache format version {}!", file.version)));
+        }
+        file.into_cache(&DeserializeOptions::default()).map_err(de::Error::custom)
+    }
+}
+
+/// Locks `<path>.lock`, shared or exclusive, blocking until the lock is available. It is released when the returned file is dropped. The cache file itself can't be locked, as saving replaces it.
+#[cfg(feature = "fs-lock")]
+fn lock_file(path: &Path, exclusive: bool) -> Result<fs::File, CacheError> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(lock_path)?;
+    if exclusive {
+        file.lock()?;
+    } else {
+        file.lock_shared()?;
+    }
+    Ok(file)
+}
+
+#[cfg(feature = "gzip")]
+fn is_gzip_path(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("gz"))
+}
+
+/// Writes `bytes` to a temporary file beside `path`, then renames it into place. The temporary file is named after the process and a counter, so concurrent writers don't share it.
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), CacheError> {
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".{}.{}.tmp", std::process::id(), WRITES.fetch_add(1, Ordering::Relaxed)));
+    fs::write(&temp, bytes)?;
+    if let Err(e) = fs::rename(&temp, path) {
+        let _ = fs::remove_file(&temp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Like `write_atomic`, holding the exclusive lock on `path` with the `fs-lock` feature.
+pub(crate) fn write_locked(path: &Path, bytes: &[u8]) -> Result<(), CacheError> {
+    #[cfg(feature = "fs-lock")]
+    let _lock = lock_file(path, true)?;
+    write_atomic(path, bytes)
+}
+
+/// Writes a file migrated by `load` back, taking the lock unless the caller holds it already.
+fn write_back(path: &Path, bytes: &[u8], locked: bool) -> Result<(), CacheError> {
+    if locked {
+        write_atomic(path, bytes)
+    } else {
+        write_locked(path, bytes)
+    }
+}
+
+fn shard_paths(dir: &Path) -> Result<Vec<PathBuf>, CacheError> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_shard = path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("shard_") && name.ends_with(".json"));
+        if is_shard {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Small seeded PRNG, good enough for reproducible sampling.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// Rejects properties with a non-positive `cid`, which PubChem-backed entries never have.
+fn check_cid(props: &Properties) -> Result<(), CacheError> {
+    if props.cid > 0 {
+        Ok(())
+    } else {
+        Err(CacheError::InvalidCid(props.cid.to_string()))
+    }
+}
+
+fn format_version(root: &Value) -> u32 {
+    root.get("version").and_then(Value::as_u64).unwrap_or(0) as u32
+}
+
+/// Upgrades a serialized cache from format version `from` to `CACHE_FORMAT_VERSION`, one version at a time.
+pub fn migrate(mut value: Value, from: u32) -> Result<Value, CacheError> {
+    if from > CACHE_FORMAT_VERSION {
+        return Err(CacheError::UnsupportedVersion(from));
+    }
+    for version in from..CACHE_FORMAT_VERSION {
+        let root = value.as_object_mut().ok_or_else(|| CacheError::Format("The root JSON was not an object!".to_owned()))?;
+        match version {
+            // v0 -> v1: same layout, the version is now recorded
+            0 => {
+                root.insert("version".to_owned(), 1.into());
+            }
+            _ => return Err(CacheError::UnsupportedVersion(version))
+        }
+    }
+    Ok(value)
+}
+
+/// Owned, thread-friendly snapshot of a compound's `Properties`.
+///
+/// `Properties` is borrowed out of the cache, so this is what gets handed to worker threads or channels.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct SerProperties {
+    pub cid: i32,
+    pub molecular_formula: Option<String>,
+    pub molecular_weight: Option<String>,
+    pub canonical_smiles: Option<String>,
+    pub isomeric_smiles: Option<String>,
+    pub inchi: Option<String>,
+    pub inchi_key: Option<String>,
+    pub iupac_name: Option<String>,
+    pub xlogp: Option<f64>,
+    pub exact_mass: Option<String>,
+    pub monoisotopic_mass: Option<String>,
+    pub tpsa: Option<f64>,
+    pub complexity: Option<i32>,
+    pub charge: Option<i32>,
+    pub hbond_donor_count: Option<i32>,
+    pub hbond_acceptor_count: Option<i32>,
+    pub rotatable_bond_count: Option<i32>,
+    pub heavy_atom_count: Option<i32>,
+    pub isotope_atom_count: Option<i32>,
+    pub atom_stereo_count: Option<i32>,
+    pub defined_atom_stereo_count: Option<i32>,
+    pub undefined_atom_stereo_count: Option<i32>,
+    pub bond_stereo_count: Option<i32>,
+    pub defined_bond_stereo_count: Option<i32>,
+    pub undefined_bond_stereo_count: Option<i32>,
+    pub covalent_unit_count: Option<i32>,
+    pub volume_3d: Option<f64>,
+    pub x_steric_quadrupole_3d: Option<f64>,
+    pub y_steric_quadrupole_3d: Option<f64>,
+    pub z_steric_quadrupole_3d: Option<f64>,
+    pub feature_count_3d: Option<i32>,
+    pub feature_acceptor_count_3d: Option<i32>,
+    pub feature_donor_count_3d: Option<i32>,
+    pub feature_anion_count_3d: Option<i32>,
+    pub feature_cation_count_3d: Option<i32>,
+    pub feature_ring_count_3d: Option<i32>,
+    pub feature_hydrophobe_count_3d: Option<i32>,
+    pub conformer_model_rmsd_3d: Option<f64>,
+    pub effective_rotor_count_3d: Option<f64>,
+    pub conformer_count_3d: Option<i32>,
+    pub fingerprint_2d: Option<String>,
+    pub title: Option<String>
+}
+impl From<&Properties> for SerProperties {
+    fn from(props: &Properties) -> Self {
+        SerProperties {
+            cid: props.cid,
+            molecular_formula: props.molecular_formula.clone(),
+            molecular_weight: props.molecular_weight.clone(),
+            canonical_smiles: props.canonical_smiles.clone(),
+            isomeric_smiles: props.isomeric_smiles.clone(),
+            inchi: props.inchi.clone(),
+            inchi_key: props.inchi_key.clone(),
+            iupac_name: props.iupac_name.clone(),
+            xlogp: props.xlogp,
+            exact_mass: props.exact_mass.clone(),
+            monoisotopic_mass: props.monoisotopic_mass.clone(),
+            tpsa: props.tpsa,
+            complexity: props.complexity,
+            charge: props.charge,
+            hbond_donor_count: props.hbond_donor_count,
+            hbond_acceptor_count: props.hbond_acceptor_count,
+            rotatable_bond_count: props.rotatable_bond_count,
+            heavy_atom_count: props.heavy_atom_count,
+            isotope_atom_count: props.isotope_atom_count,
+            atom_stereo_count: props.atom_stereo_count,
+            defined_atom_stereo_count: props.defined_atom_stereo_count,
+            undefined_atom_stereo_count: props.undefined_atom_stereo_count,
+            bond_stereo_count: props.bond_stereo_count,
+            defined_bond_stereo_count: props.defined_bond_stereo_count,
+            undefined_bond_stereo_count: props.undefined_bond_stereo_count,
+            covalent_unit_count: props.covalent_unit_count,
+            volume_3d: props.volume_3d,
+            x_steric_quadrupole_3d: props.x_steric_quadrupole_3d,
+            y_steric_quadrupole_3d: props.y_steric_quadrupole_3d,
+            z_steric_quadrupole_3d: props.z_steric_quadrupole_3d,
+            feature_count_3d: props.feature_count_3d,
+            feature_acceptor_count_3d: props.feature_acceptor_count_3d,
+            feature_donor_count_3d: props.feature_donor_count_3d,
+            feature_anion_count_3d: props.feature_anion_count_3d,
+            feature_cation_count_3d: props.feature_cation_count_3d,
+            feature_ring_count_3d: props.feature_ring_count_3d,
+            feature_hydrophobe_count_3d: props.feature_hydrophobe_count_3d,
+            conformer_model_rmsd_3d: props.conformer_model_rmsd_3d,
+            effective_rotor_count_3d: props.effective_rotor_count_3d,
+            conformer_count_3d: props.conformer_count_3d,
+            fingerprint_2d: props.fingerprint_2d.clone(),
+            title: props.title.clone()
+        }
+    }
+}
+impl From<SerProperties> for Properties {
+    fn from(props: SerProperties) -> Self {
+        Properties {
+            cid: props.cid,
+            molecular_formula: props.molecular_formula,
+            molecular_weight: props.molecular_weight,
+            canonical_smiles: props.canonical_smiles,
+            isomeric_smiles: props.isomeric_smiles,
+            inchi: props.inchi,
+            inchi_key: props.inchi_key,
+            iupac_name: props.iupac_name,
+            xlogp: props.xlogp,
+            exact_mass: props.exact_mass,
+            monoisotopic_mass: props.monoisotopic_mass,
+            tpsa: props.tpsa,
+            complexity: props.complexity,
+            charge: props.charge,
+            hbond_donor_count: props.hbond_donor_count,
+            hbond_acceptor_count: props.hbond_acceptor_count,
+            rotatable_bond_count: props.rotatable_bond_count,
+            heavy_atom_count: props.heavy_atom_count,
+            isotope_atom_count: props.isotope_atom_count,
+            atom_stereo_count: props.atom_stereo_count,
+            defined_atom_stereo_count: props.defined_atom_stereo_count,
+            undefined_atom_stereo_count: props.undefined_atom_stereo_count,
+            bond_stereo_count: props.bond_stereo_count,
+            defined_bond_stereo_count: props.defined_bond_stereo_count,
+            undefined_bond_stereo_count: props.undefined_bond_stereo_count,
+            covalent_unit_count: props.covalent_unit_count,
+            volume_3d: props.volume_3d,
+            x_steric_quadrupole_3d: props.x_steric_quadrupole_3d,
+            y_steric_quadrupole_3d: props.y_steric_quadrupole_3d,
+            z_steric_quadrupole_3d: props.z_steric_quadrupole_3d,
+            feature_count_3d: props.feature_count_3d,
+            feature_acceptor_count_3d: props.feature_acceptor_count_3d,
+            feature_donor_count_3d: props.feature_donor_count_3d,
+            feature_anion_count_3d: props.feature_anion_count_3d,
+            feature_cation_count_3d: props.feature_cation_count_3d,
+            feature_ring_count_3d: props.feature_ring_count_3d,
+            feature_hydrophobe_count_3d: props.feature_hydrophobe_count_3d,
+            conformer_model_rmsd_3d: props.conformer_model_rmsd_3d,
+            effective_rotor_count_3d: props.effective_rotor_count_3d,
+            conformer_count_3d: props.conformer_count_3d,
+            fingerprint_2d: props.fingerprint_2d,
+            title: props.title
+        }
+    }
+}
+
+/// The key `property` is serialized under (`CompoundProperty::MolecularWeight` -> `molecular_weight`).
+pub fn property_key(property: &CompoundProperty) -> &'static str {
+    match property {
+        CompoundProperty::MolecularFormula => "molecular_formula",
+        CompoundProperty::MolecularWeight => "molecular_weight",
+        CompoundProperty::CanonicalSMILES => "canonical_smiles",
+        CompoundProperty::IsomericSMILES => "isomeric_smiles",
+        CompoundProperty::InChI => "inchi",
+        CompoundProperty::InChIKey => "inchi_key",
+        CompoundProperty::IUPACName => "iupac_name",
+        CompoundProperty::Title => "title",
+        CompoundProperty::XLogP => "xlogp",
+        CompoundProperty::ExactMass => "exact_mass",
+        CompoundProperty::MonoisotopicMass => "monoisotopic_mass",
+        CompoundProperty::TPSA => "tpsa",
+        CompoundProperty::Complexity => "complexity",
+        CompoundProperty::Charge => "charge",
+        CompoundProperty::HBondDonorCount => "hbond_donor_count",
+        CompoundProperty::HBondAcceptorCount => "hbond_acceptor_count",
+        CompoundProperty::RotatableBondCount => "rotatable_bond_count",
+        CompoundProperty::HeavyAtomCount => "heavy_atom_count",
+        CompoundProperty::IsotopeAtomCount => "isotope_atom_count",
+        CompoundProperty::AtomStereoCount => "atom_stereo_count",
+        CompoundProperty::DefinedAtomStereoCount => "defined_atom_stereo_count",
+        CompoundProperty::UndefinedAtomStereoCount => "undefined_atom_stereo_count",
+        CompoundProperty::BondStereoCount => "bond_stereo_count",
+        CompoundProperty::DefinedBondStereoCount => "defined_bond_stereo_count",
+        CompoundProperty::UndefinedBondStereoCount => "undefined_bond_stereo_count",
+        CompoundProperty::CovalentUnitCount => "covalent_unit_count",
+        CompoundProperty::Volume3D => "volume_3d",
+        CompoundProperty::XStericQuadrupole3D => "x_steric_quadrupole_3d",
+        CompoundProperty::YStericQuadrupole3D => "y_steric_quadrupole_3d",
+        CompoundProperty::ZStericQuadrupole3D => "z_steric_quadrupole_3d",
+        CompoundProperty::FeatureCount3D => "feature_count_3d",
+        CompoundProperty::FeatureAcceptorCount3D => "feature_acceptor_count_3d",
+        CompoundProperty::FeatureDonorCount3D => "feature_donor_count_3d",
+        CompoundProperty::FeatureAnionCount3D => "feature_anion_count_3d",
+        CompoundProperty::FeatureCationCount3D => "feature_cation_count_3d",
+        CompoundProperty::FeatureRingCount3D => "feature_ring_count_3d",
+        CompoundProperty::FeatureHydrophobeCount3D => "feature_hydrophobe_count_3d",
+        CompoundProperty::ConformerModelRMSD3D => "conformer_model_rmsd_3d",
+        CompoundProperty::EffectiveRotorCount3D => "effective_rotor_count_3d",
+        CompoundProperty::ConformerCount3D => "conformer_count_3d",
+        CompoundProperty::Fingerprint2D => "fingerprint_2d",
+    }
+}
+
+/// Inverse of `property_key`.
+pub fn property_from_key(key: &str) -> Option<CompoundProperty> {
+    ALL_PROPERTIES.iter().find(|property| property_key(property) == key).copied()
+}
+
+/// The properties of `props` that have a value.
+fn populated_properties(props: &Properties) -> impl Iterator<Item = CompoundProperty> + '_ {
+    ALL_PROPERTIES.iter().filter(|property| !property_value(props, property).is_null()).copied()
+}
+
+/// The value of `property` in `props` as it's written by `serialize`, or `null` if it's missing.
+pub fn property_value(props: &Properties, property: &CompoundProperty) -> Value {
+    match property {
+        CompoundProperty::MolecularFormula => props.molecular_formula.clone().into(),
+        CompoundProperty::MolecularWeight => props.molecular_weight.clone().into(),
+        CompoundProperty::CanonicalSMILES => props.canonical_smiles.clone().into(),
+        CompoundProperty::IsomericSMILES => props.isomeric_smiles.clone().into(),
+        CompoundProperty::InChI => props.inchi.clone().into(),
+        CompoundProperty::InChIKey => props.inchi_key.clone().into(),
+        CompoundProperty::IUPACName => props.iupac_name.clone().into(),
+        CompoundProperty::Title => props.title.clone().into(),
+        CompoundProperty::XLogP => props.xlogp.into(),
+        CompoundProperty::ExactMass => props.exact_mass.clone().into(),
+        CompoundProperty::MonoisotopicMass => props.monoisotopic_mass.clone().into(),
+        CompoundProperty::TPSA => props.tpsa.into(),
+        CompoundProperty::Complexity => props.complexity.into(),
+        CompoundProperty::Charge => props.charge.into(),
+        CompoundProperty::HBondDonorCount => props.hbond_donor_count.into(),
+        CompoundProperty::HBondAcceptorCount => props.hbond_acceptor_count.into(),
+        CompoundProperty::RotatableBondCount => props.rotatable_bond_count.into(),
+        CompoundProperty::HeavyAtomCount => props.heavy_atom_count.into(),
+        CompoundProperty::IsotopeAtomCount => props.isotope_atom_count.into(),
+        CompoundProperty::AtomStereoCount => props.atom_stereo_count.into(),
+        CompoundProperty::DefinedAtomStereoCount => props.defined_atom_stereo_count.into(),
+        CompoundProperty::UndefinedAtomStereoCount => props.undefined_atom_stereo_count.into(),
+        CompoundProperty::BondStereoCount => props.bond_stereo_count.into(),
+        CompoundProperty::DefinedBondStereoCount => props.defined_bond_stereo_count.into(),
+        CompoundProperty::UndefinedBondStereoCount => props.undefined_bond_stereo_count.into(),
+        CompoundProperty::CovalentUnitCount => props.covalent_unit_count.into(),
+        CompoundProperty::Volume3D => props.volume_3d.into(),
+        CompoundProperty::XStericQuadrupole3D => props.x_steric_quadrupole_3d.into(),
+        CompoundProperty::YStericQuadrupole3D => props.y_steric_quadrupole_3d.into(),
+        CompoundProperty::ZStericQuadrupole3D => props.z_steric_quadrupole_3d.into(),
+        CompoundProperty::FeatureCount3D => props.feature_count_3d.into(),
+        CompoundProperty::FeatureAcceptorCount3D => props.feature_acceptor_count_3d.into(),
+        CompoundProperty::FeatureDonorCount3D => props.feature_donor_count_3d.into(),
+        CompoundProperty::FeatureAnionCount3D => props.feature_anion_count_3d.into(),
+        CompoundProperty::FeatureCationCount3D => props.feature_cation_count_3d.into(),
+        CompoundProperty::FeatureRingCount3D => props.feature_ring_count_3d.into(),
+        CompoundProperty::FeatureHydrophobeCount3D => props.feature_hydrophobe_count_3d.into(),
+        CompoundProperty::ConformerModelRMSD3D => props.conformer_model_rmsd_3d.into(),
+        CompoundProperty::EffectiveRotorCount3D => props.effective_rotor_count_3d.into(),
+        CompoundProperty::ConformerCount3D => props.conformer_count_3d.into(),
+        CompoundProperty::Fingerprint2D => props.fingerprint_2d.clone().into(),
+    }
+}
+
+/// The value of `property` if it's a floating-point field. Unlike `property_value`, this tells a NaN or infinity (written as `null`) from a missing value.
+fn float_value(props: &Properties, property: &CompoundProperty) -> Option<f64> {
+    match property {
+        CompoundProperty::XLogP => props.xlogp,
+        CompoundProperty::TPSA => props.tpsa,
+        CompoundProperty::Volume3D => props.volume_3d,
+        CompoundProperty::XStericQuadrupole3D => props.x_steric_quadrupole_3d,
+        CompoundProperty::YStericQuadrupole3D => props.y_steric_quadrupole_3d,
+        CompoundProperty::ZStericQuadrupole3D => props.z_steric_quadrupole_3d,
+        CompoundProperty::ConformerModelRMSD3D => props.conformer_model_rmsd_3d,
+        CompoundProperty::EffectiveRotorCount3D => props.effective_rotor_count_3d,
+        _ => None
+    }
+}
+
+/// A human-readable report of every property that differs between `old` and `new`, one `field: old -> new` line each, e.g. for logging what an `overwrite` changed. Values are written as JSON, so a missing value shows as `null`.
+pub fn format_diff(old: &Properties, new: &Properties) -> String {
+    let mut report = String::new();
+    if old.cid != new.cid {
+        report.push_str(&format!("cid: {} -> {}\n", old.cid, new.cid));
+    }
+    for property in ALL_PROPERTIES {
+        let (before, after) = (property_value(old, property), property_value(new, property));
+        if before != after {
+            report.push_str(&format!("{}: {before} -> {after}\n", property_key(property)));
+        }
+    }
+    report
+}
+
+/// Sets the field corresponding to `property` to `None`.
+pub fn clear_property(props: &mut Properties, property: &CompoundProperty) {
+    match property {
+        CompoundProperty::MolecularFormula => props.molecular_formula = None,
+        CompoundProperty::MolecularWeight => props.molecular_weight = None,
+        CompoundProperty::CanonicalSMILES => props.canonical_smiles = None,
+        CompoundProperty::IsomericSMILES => props.isomeric_smiles = None,
+        CompoundProperty::InChI => props.inchi = None,
+        CompoundProperty::InChIKey => props.inchi_key = None,
+        CompoundProperty::IUPACName => props.iupac_name = None,
+        CompoundProperty::Title => props.title = None,
+        CompoundProperty::XLogP => props.xlogp = None,
+        CompoundProperty::ExactMass => props.exact_mass = None,
+        CompoundProperty::MonoisotopicMass => props.monoisotopic_mass = None,
+        CompoundProperty::TPSA => props.tpsa = None,
+        CompoundProperty::Complexity => props.complexity = None,
+        CompoundProperty::Charge => props.charge = None,
+        CompoundProperty::HBondDonorCount => props.hbond_donor_count = None,
+        CompoundProperty::HBondAcceptorCount => props.hbond_acceptor_count = None,
+        CompoundProperty::RotatableBondCount => props.rotatable_bond_count = None,
+        CompoundProperty::HeavyAtomCount => props.heavy_atom_count = None,
+        CompoundProperty::IsotopeAtomCount => props.isotope_atom_count = None,
+        CompoundProperty::AtomStereoCount => props.atom_stereo_count = None,
+        CompoundProperty::DefinedAtomStereoCount => props.defined_atom_stereo_count = None,
+        CompoundProperty::UndefinedAtomStereoCount => props.undefined_atom_stereo_count = None,
+        CompoundProperty::BondStereoCount => props.bond_stereo_count = None,
+        CompoundProperty::DefinedBondStereoCount => props.defined_bond_stereo_count = None,
+        CompoundProperty::UndefinedBondStereoCount => props.undefined_bond_stereo_count = None,
+        CompoundProperty::CovalentUnitCount => props.covalent_unit_count = None,
+        CompoundProperty::Volume3D => props.volume_3d = None,
+        CompoundProperty::XStericQuadrupole3D => props.x_steric_quadrupole_3d = None,
+        CompoundProperty::YStericQuadrupole3D => props.y_steric_quadrupole_3d = None,
+        CompoundProperty::ZStericQuadrupole3D => props.z_steric_quadrupole_3d = None,
+        CompoundProperty::FeatureCount3D => props.feature_count_3d = None,
+        CompoundProperty::FeatureAcceptorCount3D => props.feature_acceptor_count_3d = None,
+        CompoundProperty::FeatureDonorCount3D => props.feature_donor_count_3d = None,
+        CompoundProperty::FeatureAnionCount3D => props.feature_anion_count_3d = None,
+        CompoundProperty::FeatureCationCount3D => props.feature_cation_count_3d = None,
+        CompoundProperty::FeatureRingCount3D => props.feature_ring_count_3d = None,
+        CompoundProperty::FeatureHydrophobeCount3D => props.feature_hydrophobe_count_3d = None,
+        CompoundProperty::ConformerModelRMSD3D => props.conformer_model_rmsd_3d = None,
+        CompoundProperty::EffectiveRotorCount3D => props.effective_rotor_count_3d = None,
+        CompoundProperty::ConformerCount3D => props.conformer_count_3d = None,
+        CompoundProperty::Fingerprint2D => props.fingerprint_2d = None,
+    }
+}
+
+/// Maps a PubChem PropertyTable key (`MolecularWeight`) to the key used in serialized caches (`molecular_weight`).
+pub fn pubchem_property_key(key: &str) -> Option<&'static str> {
+    Some(match key {
+        "CID" => "cid",
+        "MolecularFormula" => "molecular_formula",
+        "MolecularWeight" => "molecular_weight",
+        "CanonicalSMILES" => "canonical_smiles",
+        "IsomericSMILES" => "isomeric_smiles",
+        "InChI" => "inchi",
+        "InChIKey" => "inchi_key",
+        "IUPACName" => "iupac_name",
+        "Title" => "title",
+        "XLogP" => "xlogp",
+        "ExactMass" => "exact_mass",
+        "MonoisotopicMass" => "monoisotopic_mass",
+        "TPSA" => "tpsa",
+        "Complexity" => "complexity",
+        "Charge" => "charge",
+        "HBondDonorCount" => "hbond_donor_count",
+        "HBondAcceptorCount" => "hbond_acceptor_count",
+        "RotatableBondCount" => "rotatable_bond_count",
+        "HeavyAtomCount" => "heavy_atom_count",
+        "IsotopeAtomCount" => "isotope_atom_count",
+        "AtomStereoCount" => "atom_stereo_count",
+        "DefinedAtomStereoCount" => "defined_atom_stereo_count",
+        "UndefinedAtomStereoCount" => "undefined_atom_stereo_count",
+        "BondStereoCount" => "bond_stereo_count",
+        "DefinedBondStereoCount" => "defined_bond_stereo_count",
+        "UndefinedBondStereoCount" => "undefined_bond_stereo_count",
+        "CovalentUnitCount" => "covalent_unit_count",
+        "Volume3D" => "volume_3d",
+        "XStericQuadrupole3D" => "x_steric_quadrupole_3d",
+        "YStericQuadrupole3D" => "y_steric_quadrupole_3d",
+        "ZStericQuadrupole3D" => "z_steric_quadrupole_3d",
+        "FeatureCount3D" => "feature_count_3d",
+        "FeatureAcceptorCount3D" => "feature_acceptor_count_3d",
+        "FeatureDonorCount3D" => "feature_donor_count_3d",
+        "FeatureAnionCount3D" => "feature_anion_count_3d",
+        "FeatureCationCount3D" => "feature_cation_count_3d",
+        "FeatureRingCount3D" => "feature_ring_count_3d",
+        "FeatureHydrophobeCount3D" => "feature_hydrophobe_count_3d",
+        "ConformerModelRMSD3D" => "conformer_model_rmsd_3d",
+        "EffectiveRotorCount3D" => "effective_rotor_count_3d",
+        "ConformerCount3D" => "conformer_count_3d",
+        "Fingerprint2D" => "fingerprint_2d",
+        _ => return None
+    })
+}
+
+/// `None` if the key is absent, `null`, or not an integer.
+pub fn get_opt_i32(obj: &Map<String, Value>, key: &str) -> Option<i32> {
+    obj.get(key)?.as_i64().map(|v| v as i32)
+}
+
+/// `None` if the key is absent, `null`, or not a number.
+pub fn get_opt_f64(obj: &Map<String, Value>, key: &str) -> Option<f64> {
+    obj.get(key)?.as_f64()
+}
+
+/// `None` if the key is absent or `null`. Numbers are accepted and stringified, as PubChem has sent masses both ways.
+pub fn get_opt_string(obj: &Map<String, Value>, key: &str) -> Option<String> {
+    match obj.get(key)? {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None
+    }
+}
+
+/// Builds `Properties` from a snake_case property object, leaving absent or `null` fields as `None`. Only `cid` is required.
+fn properties_from_object(obj: &Map<String, Value>) -> Result<Properties, CacheError> {
+    Ok(Properties {
+        cid: get_opt_i32(obj, "cid").ok_or(CacheError::MissingField("cid"))?,
+        molecular_formula: get_opt_string(obj, "molecular_formula"),
+        molecular_weight: get_opt_string(obj, "molecular_weight"),
+        canonical_smiles: get_opt_string(obj, "canonical_smiles"),
+        isomeric_smiles: get_opt_string(obj, "isomeric_smiles"),
+        inchi: get_opt_string(obj, "inchi"),
+        inchi_key: get_opt_string(obj, "inchi_key"),
+        iupac_name: get_opt_string(obj, "iupac_name"),
+        xlogp: get_opt_f64(obj, "xlogp"),
+        exact_mass: get_opt_string(obj, "exact_mass"),
+        monoisotopic_mass: get_opt_string(obj, "monoisotopic_mass"),
+        tpsa: get_opt_f64(obj, "tpsa"),
+        complexity: get_opt_i32(obj, "complexity"),
+        charge: get_opt_i32(obj, "charge"),
+        hbond_donor_count: get_opt_i32(obj, "hbond_donor_count"),
+        hbond_acceptor_count: get_opt_i32(obj, "hbond_acceptor_count"),
+        rotatable_bond_count: get_opt_i32(obj, "rotatable_bond_count"),
+        heavy_atom_count: get_opt_i32(obj, "heavy_atom_count"),
+        isotope_atom_count: get_opt_i32(obj, "isotope_atom_count"),
+        atom_stereo_count: get_opt_i32(obj, "atom_stereo_count"),
+        defined_atom_stereo_count: get_opt_i32(obj, "defined_atom_stereo_count"),
+        undefined_atom_stereo_count: get_opt_i32(obj, "undefined_atom_stereo_count"),
+        bond_stereo_count: get_opt_i32(obj, "bond_stereo_count"),
+        defined_bond_stereo_count: get_opt_i32(obj, "defined_bond_stereo_count"),
+        undefined_bond_stereo_count: get_opt_i32(obj, "undefined_bond_stereo_count"),
+        covalent_unit_count: get_opt_i32(obj, "covalent_unit_count"),
+        volume_3d: get_opt_f64(obj, "volume_3d"),
+        x_steric_quadrupole_3d: get_opt_f64(obj, "x_steric_quadrupole_3d"),
+        y_steric_quadrupole_3d: get_opt_f64(obj, "y_steric_quadrupole_3d"),
+        z_steric_quadrupole_3d: get_opt_f64(obj, "z_steric_quadrupole_3d"),
+        feature_count_3d: get_opt_i32(obj, "feature_count_3d"),
+        feature_acceptor_count_3d: get_opt_i32(obj, "feature_acceptor_count_3d"),
+        feature_donor_count_3d: get_opt_i32(obj, "feature_donor_count_3d"),
+        feature_anion_count_3d: get_opt_i32(obj, "feature_anion_count_3d"),
+        feature_cation_count_3d: get_opt_i32(obj, "feature_cation_count_3d"),
+        feature_ring_count_3d: get_opt_i32(obj, "feature_ring_count_3d"),
+        feature_hydrophobe_count_3d: get_opt_i32(obj, "feature_hydrophobe_count_3d"),
+        conformer_model_rmsd_3d: get_opt_f64(obj, "conformer_model_rmsd_3d"),
+        effective_rotor_count_3d: get_opt_f64(obj, "effective_rotor_count_3d"),
+        conformer_count_3d: get_opt_i32(obj, "conformer_count_3d"),
+        fingerprint_2d: get_opt_string(obj, "fingerprint_2d"),
+        title: get_opt_string(obj, "title")
+    })
+}
+
+pub fn valify_string_op(string: &Option<String>) -> Value {
+    match string {
+        Some(st) => Value::String(st.clone()),
+        None => Value::Null
+    }
+}
+
+pub fn propclone(props: &Properties) -> Properties {
+    Properties {
+        cid: props.cid,
+        molecular_formula: props.molecular_formula.clone(),
+        molecular_weight: props.molecular_weight.clone(),
+        canonical_smiles: props.canonical_smiles.clone(),
+        isomeric_smiles: props.isomeric_smiles.clone(),
+        inchi: props.inchi.clone(),
+        inchi_key: props.inchi_key.clone(),
+        iupac_name: props.iupac_name.clone(),
+        xlogp: props.xlogp.clone(),
         exact_mass: props.exact_mass.clone(),
         monoisotopic_mass: props.monoisotopic_mass.clone(),
         tpsa: props.tpsa,
@@ -338,3 +2987,1491 @@ pub fn propclone(props: &Properties) -> Properties {
         title: props.title.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::AtomicUsize;
+
+    fn props(cid: i32) -> Properties {
+        properties_from_object(json!({"cid": cid, "title": "water", "xlogp": -0.5}).as_object().unwrap()).unwrap()
+    }
+
+    /// Answers CIDs with their own properties and anything else with water's (CID 962), parsing the key like `PubChemFetcher`.
+    #[derive(Clone, Default)]
+    struct CountingFetcher {
+        /// Requests made, retries included.
+        calls: Arc<AtomicUsize>,
+        /// `fetch_many` calls.
+        batches: Arc<AtomicUsize>,
+        /// How many more requests fail with HTTP 503 before one succeeds.
+        failures: Arc<AtomicUsize>
+    }
+    impl PropertyFetcher for CountingFetcher {
+        fn fetch(&self, cmp: &SerCompound, _: &[CompoundProperty]) -> Result<FetchedProperties, CacheError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                return Err(CacheError::Status(503));
+            }
+            cmp.to_compound()?;
+            let cid = match cmp.namespace {
+                Namespace::Cid => cmp.identifier.parse().unwrap(),
+                _ => 962
+            };
+            Ok(props(cid).into())
+        }
+        fn fetch_many(&self, cmps: &[SerCompound], properties: &[CompoundProperty]) -> Vec<Result<FetchedProperties, CacheError>> {
+            self.batches.fetch_add(1, Ordering::SeqCst);
+            cmps.iter().map(|cmp| self.fetch(cmp, properties)).collect()
+        }
+    }
+
+    fn counting_cache() -> (CompoundCache, CountingFetcher) {
+        let fetcher = CountingFetcher::default();
+        (CompoundCache::new().with_fetcher(fetcher.clone()), fetcher)
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chem_storage_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn get_hit_makes_no_request() {
+        let (mut cache, fetcher) = counting_cache();
+        assert!(!cache.get(SerCompound::new(962)).unwrap().0);
+        let (cached, props) = cache.get(SerCompound::new(962)).unwrap();
+        assert!(cached);
+        assert_eq!(props.cid, 962);
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn repeated_store_parses_once() {
+        let (mut cache, fetcher) = counting_cache();
+        assert!(cache.store(SerCompound::new(962)).unwrap());
+        assert!(!cache.store(SerCompound::new(962)).unwrap());
+        assert!(!cache.store(SerCompound::new(962)).unwrap());
+        cache.get(SerCompound::new(962)).unwrap();
+        // The fetcher is the only place the key is parsed on the way to PubChem
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn retries_server_busy() {
+        let (cache, fetcher) = counting_cache();
+        let mut cache = cache.with_retry(3, Duration::from_millis(1));
+        fetcher.failures.store(1, Ordering::SeqCst);
+        assert_eq!(cache.get(SerCompound::new(962)).unwrap().1.cid, 962);
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 2);
+
+        let (mut cache, fetcher) = counting_cache();
+        fetcher.failures.store(1, Ordering::SeqCst);
+        assert!(matches!(cache.get(SerCompound::new(962)), Err(CacheError::Status(503))));
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn classifies_transient_errors() {
+        assert!(CacheError::Status(503).is_transient());
+        assert!(CacheError::Status(429).is_transient());
+        assert!(!CacheError::Status(404).is_transient());
+        assert!(CacheError::Io(io::ErrorKind::TimedOut.into()).is_transient());
+        assert!(!CacheError::InvalidCid("x".to_owned()).is_transient());
+        assert_eq!(fault_status("PUGREST.ServerBusy: Too many requests or server too busy"), Some(503));
+        assert_eq!(fault_status("PUGREST.NotFound: No CID found"), None);
+    }
+
+    #[test]
+    fn merge_from_reader_marks_dirty_and_drops_dangling_aliases() {
+        let mut source = CompoundCache::new();
+        source.insert(SerCompound::new(962), props(962)).unwrap();
+        source.insert(SerCompound::new(5234), props(5234)).unwrap();
+        source.aliases.insert(SerCompound::with_name("water"), SerCompound::new(962));
+        source.aliases.insert(SerCompound::with_name("salt"), SerCompound::new(1));
+        let json = source.serialize().unwrap().to_string();
+
+        let mut cache = CompoundCache::new();
+        assert_eq!(cache.merge_from_reader(json.as_bytes(), false).unwrap(), 2);
+        assert!(cache.is_dirty());
+        assert_eq!(cache.resolve(&SerCompound::with_name("water")), Some(&SerCompound::new(962)));
+        assert_eq!(cache.alias_count(), 1);
+
+        // Only an alias is new
+        cache.mark_saved(None);
+        cache.aliases.clear();
+        assert_eq!(cache.merge_from_reader(json.as_bytes(), true).unwrap(), 0);
+        assert!(cache.is_dirty());
+        assert_eq!(cache.alias_count(), 1);
+
+        // Cut off after the first entry
+        let mut cache = CompoundCache::new();
+        let entries = json.find("\"cache\"").unwrap();
+        let cut = &json[..entries + json[entries..].find("}},{").unwrap() + 2];
+        assert!(cache.merge_from_reader(cut.as_bytes(), false).is_err());
+        assert_eq!(cache.len(), 1);
+        assert!(cache.is_dirty());
+    }
+
+    #[test]
+    fn prune_keeps_most_accessed() {
+        let (cache, _) = counting_cache();
+        let mut cache = cache.with_min_properties(10);
+        cache.get(SerCompound::new(1)).unwrap();
+        cache.get(SerCompound::new(2)).unwrap();
+        cache.get_ref(&SerCompound::new(2));
+        assert_eq!(cache.prune_to_most_accessed(1), 1);
+        assert!(cache.contains(&SerCompound::new(2)));
+        assert!(!cache.contains(&SerCompound::new(1)));
+        assert_eq!(cache.degraded().collect::<Vec<_>>(), [&SerCompound::new(2)]);
+    }
+
+    #[test]
+    fn get_many_batches_cids_within_budget() {
+        let (cache, fetcher) = counting_cache();
+        let mut cache = cache.with_fetch_budget(2);
+        let cmps = [SerCompound::new(1), SerCompound::new(2), SerCompound::new(3)];
+        assert!(matches!(cache.get_many(&cmps), Err(CacheError::BudgetExceeded)));
+        assert_eq!(fetcher.batches.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.fetch_budget(), Some(0));
+
+        let (mut cache, fetcher) = counting_cache();
+        let props = cache.get_many(&cmps).unwrap();
+        assert_eq!(props.iter().map(|props| props.cid).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(fetcher.batches.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn get_traced_reports_fetch_and_alias() {
+        let (cache, _) = counting_cache();
+        let mut cache = cache.with_ttl(Duration::from_millis(1));
+        let water = SerCompound::with_name("water");
+        let (steps, _) = cache.get_traced(water.clone()).unwrap();
+        assert_eq!(steps, [
+            ResolutionStep::Fetch(water.clone()),
+            ResolutionStep::AliasRegistered { alias: SerCompound::new(962), primary: water.clone() }
+        ]);
+        let (steps, _) = cache.get_traced(SerCompound::new(962)).unwrap();
+        assert_eq!(steps, [ResolutionStep::Alias { alias: SerCompound::new(962), primary: water.clone() }]);
+
+        // Expired, so the alias is fetched again under its primary
+        thread::sleep(Duration::from_millis(5));
+        let (steps, props) = cache.get_traced(SerCompound::new(962)).unwrap();
+        assert_eq!(props.cid, 962);
+        assert_eq!(steps, [ResolutionStep::Fetch(water)]);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn overwrite_returns_previous_through_alias() {
+        let (mut cache, fetcher) = counting_cache();
+        assert!(cache.overwrite(SerCompound::with_name("water")).unwrap().is_none());
+        let previous = cache.overwrite(SerCompound::new(962)).unwrap().unwrap();
+        assert_eq!(previous.cid, 962);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn from_smiles_list_reports_failures() {
+        let fetcher = CountingFetcher::default();
+        let (cache, errors) = CompoundCache::from_smiles_list_with(fetcher.clone(), &["O", "C(", "CCO"]).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains(&SerCompound::with_smiles("CCO")));
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(&errors[0], (smiles, CacheError::InvalidSmiles(_)) if smiles == "C("));
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn global_is_one_instance() {
+        assert!(std::ptr::eq(global(), global()));
+        // `CHEM_CACHE_PATH` isn't set for the tests
+        assert_eq!(global_load_error(), None);
+    }
+
+    #[test]
+    fn serializes_absent_properties_as_null() {
+        let mut cache = CompoundCache::new();
+        cache.insert(SerCompound::new(1), properties_from_object(json!({"cid": 1}).as_object().unwrap()).unwrap()).unwrap();
+        let json = cache.serialize().unwrap();
+        let props = &json["cache"][0]["properties"];
+        assert_eq!(props["cid"], 1);
+        assert!(props["molecular_weight"].is_null() && props["xlogp"].is_null());
+        let loaded = CompoundCache::deserialize(json.to_string()).unwrap();
+        assert_eq!(loaded.get_ref(&SerCompound::new(1)).unwrap().molecular_weight, None);
+    }
+
+
+    #[test]
+    fn snapshot_crosses_threads() {
+        let snapshot = SerProperties::from(&props(962));
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || tx.send(snapshot).unwrap()).join().unwrap();
+        let received = rx.recv().unwrap();
+        assert_eq!(received.cid, 962);
+        assert_eq!(received.title.as_deref(), Some("water"));
+        assert_eq!(Properties::from(received).xlogp, Some(-0.5));
+    }
+
+
+    #[test]
+    fn plan_splits_cached_stale_and_missing() {
+        let (mut cache, fetcher) = counting_cache();
+        cache.store(SerCompound::new(1)).unwrap();
+        cache.store(SerCompound::new(2)).unwrap();
+        cache.cache.get_mut(&SerCompound::new(2)).unwrap().fetched_at = SystemTime::UNIX_EPOCH;
+        cache.set_ttl(Some(Duration::from_secs(60)));
+        let batch = [SerCompound::new(1), SerCompound::new(2), SerCompound::new(3), SerCompound::with_nmsp_iden(Namespace::Cid, "x")];
+        let plan = cache.plan(&batch);
+        assert_eq!(plan.hits, [SerCompound::new(1)]);
+        assert_eq!(plan.stale, [SerCompound::new(2)]);
+        assert_eq!(plan.misses, [SerCompound::new(3)]);
+        assert_eq!(plan.invalid.len(), 1);
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 2);
+    }
+
+
+    #[test]
+    fn v0_file_is_migrated_and_rewritten() {
+        let dir = temp_dir("migrate");
+        let path = dir.join("cache.json");
+        let mut cache = CompoundCache::new();
+        cache.insert(SerCompound::new(962), props(962)).unwrap();
+        let mut v0 = cache.serialize().unwrap();
+        v0.as_object_mut().unwrap().remove("version");
+        fs::write(&path, v0.to_string()).unwrap();
+
+        let loaded = CompoundCache::load_from_path(&path).unwrap();
+        assert_eq!(loaded.get_ref(&SerCompound::new(962)).unwrap().cid, 962);
+        let rewritten: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(rewritten["version"], CACHE_FORMAT_VERSION);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn retain_namespace_drops_aliases() {
+        let (mut cache, _) = counting_cache();
+        cache.get(SerCompound::with_name("water")).unwrap();
+        cache.get(SerCompound::new(1)).unwrap();
+        assert_eq!(cache.alias_count(), 1);
+        assert_eq!(cache.retain_namespace(Namespace::Cid), 1);
+        assert_eq!(cache.alias_count(), 0);
+        assert!(cache.get_ref(&SerCompound::new(962)).is_none());
+    }
+
+
+    #[test]
+    fn fetch_budget_refuses_misses_once_spent() {
+        let (cache, fetcher) = counting_cache();
+        let mut cache = cache.with_fetch_budget(1);
+        cache.get(SerCompound::new(1)).unwrap();
+        assert!(matches!(cache.get(SerCompound::new(2)), Err(CacheError::BudgetExceeded)));
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 1);
+        // Hits don't need the budget
+        assert!(cache.get(SerCompound::new(1)).is_ok());
+    }
+
+
+    #[test]
+    fn namespace_properties_pick_what_is_fetched() {
+        /// Records the properties each request asks for.
+        struct RecordingFetcher(Arc<Mutex<Vec<Vec<CompoundProperty>>>>);
+        impl PropertyFetcher for RecordingFetcher {
+            fn fetch(&self, cmp: &SerCompound, properties: &[CompoundProperty]) -> Result<FetchedProperties, CacheError> {
+                self.0.lock().unwrap().push(properties.to_vec());
+                CountingFetcher::default().fetch(cmp, properties)
+            }
+        }
+        const LEAN: &[CompoundProperty] = &[CompoundProperty::Title, CompoundProperty::MolecularFormula];
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let mut cache = CompoundCache::new()
+            .with_fetcher(RecordingFetcher(requested.clone()))
+            .with_namespace_properties(Namespace::Name, LEAN);
+        assert_eq!(cache.properties_for(Namespace::Cid), ALL_PROPERTIES);
+        cache.get(SerCompound::with_name("water")).unwrap();
+        cache.get(SerCompound::new(1)).unwrap();
+        assert_eq!(*requested.lock().unwrap(), [LEAN.to_vec(), ALL_PROPERTIES.to_vec()]);
+    }
+
+
+    #[cfg(feature = "fuzz")]
+    #[test]
+    fn arbitrary_compounds_roundtrip() {
+        use arbitrary::{Arbitrary, Unstructured};
+        for bytes in [&[0u8; 16][..], &[1, 255, 7, 42, 3, b'a', b'b', b'c', 9, 0, 0, 1], b"\x04\x05hello, world"] {
+            let cmp = SerCompound::arbitrary(&mut Unstructured::new(bytes)).unwrap();
+            // Parsing may fail, but mustn't panic
+            let _ = cmp.to_compound();
+            let json = serde_json::to_string(&cmp).unwrap();
+            assert_eq!(serde_json::from_str::<SerCompound>(&json).unwrap(), cmp);
+        }
+    }
+
+
+    #[test]
+    fn exact_mass_of_two_waters() {
+        let mut cache = CompoundCache::new();
+        let water = properties_from_object(json!({"cid": 962, "exact_mass": "18.0106", "isotope_atom_count": 0}).as_object().unwrap()).unwrap();
+        cache.insert(SerCompound::new(962), water).unwrap();
+        let report = cache.exact_mass_report(&[(SerCompound::new(962), 2.0)]).unwrap();
+        assert!((report.exact_mass - 36.0212).abs() < 1e-9);
+        assert!(report.isotope_labeled.is_empty());
+        assert!(matches!(cache.exact_mass_report(&[(SerCompound::new(1), 1.0)]), Err(CacheError::NotCached(_))));
+    }
+
+
+    #[test]
+    fn lowercasing_normalizer_collides_names() {
+        let (cache, fetcher) = counting_cache();
+        let mut cache = cache.with_key_normalizer(|cmp| SerCompound { identifier: cmp.identifier.to_lowercase(), ..cmp });
+        cache.get(SerCompound::with_name("Water")).unwrap();
+        cache.get(SerCompound::with_name("water")).unwrap();
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 1);
+        assert!(cache.contains(&SerCompound::with_name("WATER")));
+    }
+
+
+    #[test]
+    fn four_shards_roundtrip_with_aliases() {
+        let dir = temp_dir("shards");
+        let (mut cache, _) = counting_cache();
+        for cid in 1..=10 {
+            cache.get(SerCompound::new(cid)).unwrap();
+        }
+        cache.get(SerCompound::with_name("water")).unwrap();
+        assert_eq!(cache.alias_count(), 1);
+        cache.save_sharded(&dir, 4).unwrap();
+
+        let loaded = CompoundCache::load_sharded(&dir).unwrap();
+        let mut keys: Vec<_> = loaded.iter().map(|(key, props)| (key.clone(), props.cid)).collect();
+        let mut expected: Vec<_> = cache.iter().map(|(key, props)| (key.clone(), props.cid)).collect();
+        keys.sort();
+        expected.sort();
+        assert_eq!(keys, expected);
+        assert_eq!(loaded.aliases, cache.aliases);
+        assert_eq!(loaded.get_ref(&SerCompound::with_name("water")).unwrap().cid, 962);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn allow_list_strips_3d_fields() {
+        const ONLY_2D: &[CompoundProperty] = &[CompoundProperty::MolecularFormula, CompoundProperty::Title, CompoundProperty::XLogP];
+        let mut cache = CompoundCache::new().with_allowed_properties(ONLY_2D);
+        let full = properties_from_object(json!({"cid": 2244, "title": "aspirin", "xlogp": 1.2, "volume_3d": 136.0, "feature_count_3d": 6}).as_object().unwrap()).unwrap();
+        cache.insert(SerCompound::new(2244), full).unwrap();
+        let stored = cache.get_ref(&SerCompound::new(2244)).unwrap();
+        assert_eq!((stored.title.as_deref(), stored.xlogp), (Some("aspirin"), Some(1.2)));
+        assert_eq!((stored.volume_3d, stored.feature_count_3d), (None, None));
+    }
+
+
+    #[test]
+    fn intersection_and_difference_sizes() {
+        let mut a = CompoundCache::new();
+        let mut b = CompoundCache::new();
+        for cid in 1..=3 {
+            a.insert(SerCompound::new(cid), props(cid as i32)).unwrap();
+        }
+        for cid in 2..=5 {
+            b.insert(SerCompound::new(cid), props(cid as i32)).unwrap();
+        }
+        assert_eq!(a.intersection(&b).len(), 2);
+        assert_eq!(a.difference(&b).len(), 1);
+        assert!(a.difference(&b).contains(&SerCompound::new(1)));
+        assert_eq!(b.difference(&a).len(), 2);
+    }
+
+
+    #[test]
+    fn single_atom_handles_no_heavy_atoms() {
+        let proton = properties_from_object(json!({"cid": 1038, "molecular_formula": "H+", "heavy_atom_count": 0}).as_object().unwrap()).unwrap();
+        assert!(is_single_atom(&proton));
+        assert_eq!(element_counts(&proton), Some(vec![("H".to_owned(), 1)]));
+        let water = properties_from_object(json!({"cid": 962, "molecular_formula": "H2O", "heavy_atom_count": 1}).as_object().unwrap()).unwrap();
+        assert!(!is_single_atom(&water));
+        assert!(!is_single_atom(&props(1)));
+    }
+
+
+    #[test]
+    fn custom_key_type() {
+        #[derive(Clone, PartialEq, Eq, Hash, Debug)]
+        struct InHouseId(String);
+        impl CacheKey for InHouseId {}
+
+        let mut cache: CompoundCache<InHouseId> = CompoundCache::empty();
+        cache.insert(InHouseId("LAB-0001".to_owned()), props(100)).unwrap();
+        assert_eq!(cache.get_ref(&InHouseId("LAB-0001".to_owned())).unwrap().title.as_deref(), Some("water"));
+        assert!(!cache.contains(&InHouseId("LAB-0002".to_owned())));
+    }
+
+
+    #[test]
+    fn csv_import_reports_rows_and_batches_cids() {
+        let (mut cache, fetcher) = counting_cache();
+        let mut rows = Vec::new();
+        let csv = "namespace,identifier\ncid,1\nname,water\ncid,2\n\ncid,1\n";
+        cache.cache_from_csv_reader(csv.as_bytes(), |cmp, result| rows.push((cmp.to_string(), result.is_ok()))).unwrap();
+        rows.sort();
+        assert_eq!(rows, [("cid:1", true), ("cid:1", true), ("cid:2", true), ("name:water", true)].map(|(cmp, ok)| (cmp.to_owned(), ok)));
+        assert_eq!(fetcher.batches.load(Ordering::SeqCst), 1);
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 3);
+        assert!(cache.cache_from_csv_reader("foo,1\n".as_bytes(), |_, _| ()).is_err());
+    }
+
+
+    #[test]
+    fn imports_pascal_case_property_table() {
+        let mut cache = CompoundCache::new();
+        let table = json!({"PropertyTable": {"Properties": [{"CID": 962, "MolecularWeight": "18.015", "MolecularFormula": "H2O"}]}});
+        assert_eq!(cache.import_pubchem_property_table(&table).unwrap(), 1);
+        let water = cache.get_ref(&SerCompound::new(962)).unwrap();
+        assert_eq!(water.molecular_weight.as_deref(), Some("18.015"));
+        assert_eq!(water.molecular_formula.as_deref(), Some("H2O"));
+    }
+
+
+    #[test]
+    fn touch_resets_age() {
+        let mut cache = CompoundCache::new().with_ttl(Duration::from_secs(60));
+        cache.insert(SerCompound::new(962), props(962)).unwrap();
+        cache.cache.get_mut(&SerCompound::new(962)).unwrap().fetched_at = SystemTime::now() - Duration::from_secs(3600);
+        assert!(cache.get_noreq_fresh(&SerCompound::new(962)).is_none());
+        assert!(cache.touch(&SerCompound::new(962)));
+        assert!(cache.age(&SerCompound::new(962)).unwrap() < Duration::from_secs(60));
+        assert!(cache.get_noreq_fresh(&SerCompound::new(962)).is_some());
+        assert!(!cache.touch(&SerCompound::new(1)));
+    }
+
+
+    #[test]
+    fn identity_map_has_cid_per_key() {
+        let (mut cache, _) = counting_cache();
+        cache.get(SerCompound::with_name("water")).unwrap();
+        cache.get(SerCompound::new(1)).unwrap();
+        assert_eq!(cache.to_identity_map(), json!({"name:water": 962, "cid:962": 962, "cid:1": 1}));
+    }
+
+
+    #[test]
+    fn rejects_non_positive_cid() {
+        let mut cache = CompoundCache::new();
+        assert!(matches!(cache.insert(SerCompound::new(1), props(0)), Err(CacheError::InvalidCid(_))));
+        assert!(cache.is_empty());
+        cache.insert_unchecked(SerCompound::new(1), props(0));
+        assert_eq!(cache.len(), 1);
+    }
+
+
+    #[test]
+    fn iter_mut_edits_persist() {
+        let mut cache = CompoundCache::new();
+        for cid in [1, 2] {
+            let charged = properties_from_object(json!({"cid": cid, "charge": 1}).as_object().unwrap()).unwrap();
+            cache.insert(SerCompound::new(cid as u32), charged).unwrap();
+        }
+        cache.mark_saved(None);
+        for (_, props) in cache.iter_mut() {
+            props.charge = Some(0);
+        }
+        assert!(cache.is_dirty());
+        assert!(cache.iter().all(|(_, props)| props.charge == Some(0)));
+    }
+
+
+    #[test]
+    fn canonicalizes_keys_and_aliases() {
+        let mut cache = CompoundCache::new();
+        cache.insert(SerCompound::with_name("water"), props(962)).unwrap();
+        cache.aliases.insert(SerCompound::with_name("oxidane"), SerCompound::with_name("water"));
+        let options = DeserializeOptions { canonicalize_to_cid: true };
+        let loaded = CompoundCache::deserialize_with_options(cache.serialize().unwrap().to_string(), &options).unwrap();
+        assert!(loaded.cache.contains_key(&SerCompound::new(962)));
+        assert_eq!(loaded.resolve(&SerCompound::with_name("water")), Some(&SerCompound::new(962)));
+        assert_eq!(loaded.resolve(&SerCompound::with_name("oxidane")), Some(&SerCompound::new(962)));
+    }
+
+
+    #[test]
+    fn serialized_generation_time_is_read_back() {
+        let mut cache = CompoundCache::new();
+        cache.insert(SerCompound::new(962), props(962)).unwrap();
+        assert_eq!(cache.generated_at(), None);
+        let json = cache.serialize().unwrap();
+        assert!(json["generated_at"].is_string());
+        let loaded = CompoundCache::deserialize(json.to_string()).unwrap();
+        let age = loaded.generated_at().unwrap().elapsed().unwrap_or_default();
+        assert!(age < Duration::from_secs(60));
+    }
+
+
+    #[test]
+    fn formats_roundtrip_and_dispatch_on_extension() {
+        let mut cache = CompoundCache::new();
+        cache.insert(SerCompound::new(962), props(962)).unwrap();
+        let json: &dyn CacheFormat = &JsonFormat;
+        let loaded = json.deserialize(&json.serialize(&cache).unwrap()).unwrap();
+        assert_eq!(loaded.get_ref(&SerCompound::new(962)).unwrap().cid, 962);
+
+        assert_eq!(format::format_for_path(Path::new("cache.JSON")).unwrap().extensions(), ["json"]);
+        assert!(format::format_for_path(Path::new("cache.toml")).is_none());
+        let dir = temp_dir("formats");
+        format::save(&cache, dir.join("cache.json"), None).unwrap();
+        assert_eq!(format::load(dir.join("cache.json"), None).unwrap().len(), 1);
+        assert!(matches!(format::save(&cache, dir.join("cache.toml"), None), Err(CacheError::UnsupportedFormat(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn seed_file_is_read_but_never_serialized() {
+        let dir = temp_dir("seed");
+        let seed = dir.join("seed.json");
+        let mut baseline = CompoundCache::new();
+        baseline.insert(SerCompound::with_name("water"), props(962)).unwrap();
+        baseline.save_to_path(&seed).unwrap();
+
+        let mut cache = CompoundCache::with_seed_file(&seed).unwrap();
+        cache.insert(SerCompound::with_name("ethanol"), props(702)).unwrap();
+        assert!(cache.get_ref(&SerCompound::with_name("water")).is_some());
+        assert!(cache.get_ref(&SerCompound::with_name("ethanol")).is_some());
+        let json = cache.serialize().unwrap();
+        assert_eq!(json["cache"].as_array().unwrap().len(), 1);
+        assert_eq!(json["cache"][0]["properties"]["cid"], 702);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn annotations_are_stored_and_roundtripped() {
+        struct AnnotatingFetcher;
+        impl PropertyFetcher for AnnotatingFetcher {
+            fn fetch(&self, _: &SerCompound, _: &[CompoundProperty]) -> Result<FetchedProperties, CacheError> {
+                let annotations = HashMap::from([(CompoundProperty::XLogP, "could not be standardized".to_owned())]);
+                Ok(FetchedProperties { props: props(962), annotations })
+            }
+        }
+        let mut cache = CompoundCache::new().with_fetcher(AnnotatingFetcher);
+        cache.get(SerCompound::new(962)).unwrap();
+        let loaded = CompoundCache::deserialize(cache.serialize().unwrap().to_string()).unwrap();
+        for cache in [&cache, &loaded] {
+            let annotations = cache.annotations(&SerCompound::new(962)).unwrap();
+            assert_eq!(annotations[&CompoundProperty::XLogP], "could not be standardized");
+        }
+    }
+
+
+    #[test]
+    fn sample_is_reproducible() {
+        let mut cache = CompoundCache::new();
+        for cid in 1..=20 {
+            cache.insert(SerCompound::new(cid), props(cid as i32)).unwrap();
+        }
+        let sample = cache.sample(5, 42);
+        assert_eq!(sample.len(), 5);
+        assert_eq!(sample, cache.sample(5, 42));
+        assert_eq!(cache.sample(100, 7).len(), 20);
+    }
+
+
+    #[test]
+    #[allow(deprecated)]
+    fn get_noreq_still_wraps_hits() {
+        let mut cache = CompoundCache::new();
+        cache.insert(SerCompound::new(962), props(962)).unwrap();
+        assert_eq!(cache.get_noreq(SerCompound::new(962)).unwrap().unwrap().cid, 962);
+        assert!(cache.get_noreq(SerCompound::new(1)).unwrap().is_none());
+    }
+
+
+    #[test]
+    fn fetch_time_estimate_scales_with_misses() {
+        struct SlowFetcher;
+        impl PropertyFetcher for SlowFetcher {
+            fn fetch(&self, cmp: &SerCompound, properties: &[CompoundProperty]) -> Result<FetchedProperties, CacheError> {
+                thread::sleep(Duration::from_millis(10));
+                CountingFetcher::default().fetch(cmp, properties)
+            }
+        }
+        let mut cache = CompoundCache::new().with_fetcher(SlowFetcher);
+        assert_eq!(cache.estimate_fetch_time(10), Duration::ZERO);
+        cache.get(SerCompound::new(1)).unwrap();
+        cache.get(SerCompound::new(2)).unwrap();
+        let latency = cache.average_fetch_latency().unwrap();
+        assert!(latency >= Duration::from_millis(10));
+        assert_eq!(cache.estimate_fetch_time(10), latency * 10);
+        assert!(cache.estimate_fetch_time(20) > cache.estimate_fetch_time(10));
+        assert_eq!(cache.estimate_fetch_time(0), Duration::ZERO);
+    }
+
+
+    #[test]
+    fn json_size_estimate_is_close() {
+        let (mut cache, _) = counting_cache();
+        for cid in 1..=20 {
+            cache.get(SerCompound::new(cid)).unwrap();
+        }
+        cache.get(SerCompound::with_name("water")).unwrap();
+        let estimate = cache.estimated_json_bytes() as f64;
+        let actual = cache.serialize().unwrap().to_string().len() as f64;
+        assert!(estimate > actual / 2.0 && estimate < actual * 2.0, "estimated {estimate}, actual {actual}");
+    }
+
+
+    #[test]
+    fn roundtrip() {
+        let mut cache = CompoundCache::new();
+        cache.insert(SerCompound::new(962), props(962)).unwrap();
+        cache.validate_roundtrip().unwrap();
+        let loaded = CompoundCache::deserialize(cache.serialize().unwrap().to_string()).unwrap();
+        assert_eq!(loaded.get_ref(&SerCompound::new(962)).unwrap().title.as_deref(), Some("water"));
+
+        let mut nan = props(962);
+        nan.xlogp = Some(f64::NAN);
+        cache.insert(SerCompound::new(962), nan).unwrap();
+        let e = cache.validate_roundtrip().unwrap_err().to_string();
+        assert!(e.contains("xlogp"), "{e}");
+    }
+
+
+    #[test]
+    fn hill_order() {
+        assert_eq!(hill_formula("O4C9H8").as_deref(), Some("C9H8O4"));
+        assert_eq!(hill_formula("OH2").as_deref(), Some("H2O"));
+        let aspirin = properties_from_object(json!({"cid": 2244, "molecular_formula": "O4C9H8"}).as_object().unwrap()).unwrap();
+        assert_eq!(molecular_formula_hill(&aspirin).as_deref(), Some("C9H8O4"));
+        assert_eq!(molecular_formula_hill(&props(1)), None);
+    }
+
+
+    #[test]
+    fn dirty_flag_and_save_skipping() {
+        let dir = temp_dir("dirty");
+        let (a, b) = (dir.join("a.json"), dir.join("b.json"));
+        let mut cache = CompoundCache::new();
+        assert!(!cache.is_dirty());
+        cache.insert(SerCompound::new(962), props(962)).unwrap();
+        assert!(cache.is_dirty());
+        cache.save_to_path(&a).unwrap();
+        assert!(!cache.is_dirty());
+
+        let loaded = CompoundCache::load_from_path(&a).unwrap();
+        assert!(!loaded.is_dirty());
+        // Clean, but `b` doesn't hold the cache yet
+        loaded.save_to_path(&b).unwrap();
+        assert!(b.exists());
+        fs::remove_file(&b).unwrap();
+        loaded.save_to_path(&b).unwrap();
+        assert!(!b.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn merged_cid_is_redirected_and_aliased() {
+        struct RedirectFetcher(CountingFetcher);
+        impl PropertyFetcher for RedirectFetcher {
+            fn fetch(&self, cmp: &SerCompound, properties: &[CompoundProperty]) -> Result<FetchedProperties, CacheError> {
+                let cmp = if *cmp == SerCompound::new(100) { SerCompound::new(200) } else { cmp.clone() };
+                self.0.fetch(&cmp, properties)
+            }
+        }
+        let fetcher = CountingFetcher::default();
+        let mut cache = CompoundCache::new().with_fetcher(RedirectFetcher(fetcher.clone()));
+        assert_eq!(cache.get(SerCompound::new(100)).unwrap().1.cid, 200);
+        assert!(cache.cache.contains_key(&SerCompound::new(200)));
+        assert!(!cache.cache.contains_key(&SerCompound::new(100)));
+        assert_eq!(cache.aliases[&SerCompound::new(100)], SerCompound::new(200));
+        assert_eq!(cache.get(SerCompound::new(100)).unwrap().1.cid, 200);
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 1);
+    }
+
+
+    #[test]
+    fn diff_report_lists_changed_xlogp() {
+        let mut new = props(962);
+        new.xlogp = Some(-0.7);
+        let report = format_diff(&props(962), &new);
+        assert!(report.lines().any(|line| line.starts_with("xlogp:") && line.contains("-0.5") && line.contains("-0.7")), "{report}");
+        assert_eq!(report.lines().count(), 1);
+        assert_eq!(format_diff(&new, &new), "");
+    }
+
+
+    #[test]
+    fn depiction_is_cached_and_roundtripped() {
+        struct ImageFetcher(CountingFetcher);
+        impl PropertyFetcher for ImageFetcher {
+            fn fetch(&self, cmp: &SerCompound, properties: &[CompoundProperty]) -> Result<FetchedProperties, CacheError> {
+                self.0.fetch(cmp, properties)
+            }
+            fn fetch_image(&self, _: &SerCompound) -> Result<Vec<u8>, CacheError> {
+                self.0.calls.fetch_add(1, Ordering::SeqCst);
+                Ok(b"\x89PNG fake".to_vec())
+            }
+        }
+        let fetcher = CountingFetcher::default();
+        let mut cache = CompoundCache::new().with_fetcher(ImageFetcher(fetcher.clone()));
+        assert_eq!(cache.get_image(SerCompound::new(962)).unwrap(), b"\x89PNG fake");
+        assert_eq!(cache.get_image(SerCompound::new(962)).unwrap(), b"\x89PNG fake");
+        // One for the properties, one for the image
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 2);
+        let json = cache.serialize().unwrap();
+        assert!(json["cache"][0]["image_png"].is_string());
+        let mut loaded = CompoundCache::deserialize(json.to_string()).unwrap().with_fetcher(ImageFetcher(fetcher.clone()));
+        assert_eq!(loaded.get_image(SerCompound::new(962)).unwrap(), b"\x89PNG fake");
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 2);
+    }
+
+
+    #[test]
+    fn get_any_falls_back_to_smiles() {
+        struct NoNames(CountingFetcher);
+        impl PropertyFetcher for NoNames {
+            fn fetch(&self, cmp: &SerCompound, properties: &[CompoundProperty]) -> Result<FetchedProperties, CacheError> {
+                match cmp.namespace {
+                    Namespace::Name => Err(CacheError::Status(404)),
+                    _ => self.0.fetch(cmp, properties)
+                }
+            }
+        }
+        let mut cache = CompoundCache::new().with_fetcher(NoNames(CountingFetcher::default()));
+        let ids = [SerCompound::with_name("wter"), SerCompound::with_smiles("O")];
+        assert_eq!(cache.get_any(&ids).unwrap().cid, 962);
+        assert!(cache.contains(&SerCompound::with_name("wter")));
+        assert!(matches!(cache.get_any(&[SerCompound::with_name("nothing")]), Err(CacheError::AllFailed(errors)) if errors.len() == 1));
+    }
+
+
+    #[test]
+    fn source_survives_aliasing_and_serialization() {
+        let (mut cache, _) = counting_cache();
+        cache.get(SerCompound::with_name("water")).unwrap();
+        let name = SerCompound::with_name("water");
+        assert_eq!(cache.source_of(&SerCompound::new(962)), Some(&name));
+        let loaded = CompoundCache::deserialize(cache.serialize().unwrap().to_string()).unwrap();
+        assert_eq!(loaded.source_of(&SerCompound::new(962)), Some(&name));
+        cache.insert(SerCompound::new(1), props(1)).unwrap();
+        assert_eq!(cache.source_of(&SerCompound::new(1)), None);
+    }
+
+
+    #[cfg(feature = "rmp")]
+    #[test]
+    fn msgpack_roundtrip_matches_json() {
+        let (mut cache, _) = counting_cache();
+        cache.get(SerCompound::with_name("water")).unwrap();
+        cache.get(SerCompound::new(1)).unwrap();
+        let via_msgpack = CompoundCache::from_msgpack(&cache.to_msgpack().unwrap()).unwrap();
+        let via_json = CompoundCache::deserialize(cache.serialize().unwrap().to_string()).unwrap();
+        // Entry order follows the `HashMap`, so compare sorted
+        let sorted = |cache: &CompoundCache, field: &str| {
+            let mut items: Vec<String> = cache.serialize().unwrap()[field].as_array().unwrap().iter().map(Value::to_string).collect();
+            items.sort();
+            items
+        };
+        for field in ["cache", "aliases"] {
+            assert_eq!(sorted(&via_msgpack, field), sorted(&via_json, field));
+        }
+        assert_eq!(via_msgpack.len(), 2);
+    }
+
+
+    #[test]
+    fn retry_failed_promotes_successes() {
+        let (cache, fetcher) = counting_cache();
+        let mut cache = cache.with_negative_caching();
+        fetcher.failures.store(1, Ordering::SeqCst);
+        assert!(cache.get(SerCompound::new(962)).is_err());
+        assert!(matches!(cache.get(SerCompound::new(962)), Err(CacheError::PreviouslyFailed(_))));
+        assert_eq!(cache.failed().count(), 1);
+
+        let results = cache.retry_failed();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].1.is_ok());
+        assert_eq!(cache.failed().count(), 0);
+        assert_eq!(cache.get_ref(&SerCompound::new(962)).unwrap().cid, 962);
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 2);
+    }
+
+
+    #[test]
+    fn long_fingerprint_makes_a_bigger_entry() {
+        let mut cache = CompoundCache::new();
+        cache.insert(SerCompound::new(1), props(1)).unwrap();
+        let mut fingerprinted = props(2);
+        fingerprinted.fingerprint_2d = Some("A".repeat(1000));
+        cache.insert(SerCompound::new(2), fingerprinted).unwrap();
+        let small = cache.entry_json_bytes(&SerCompound::new(1)).unwrap();
+        let big = cache.entry_json_bytes(&SerCompound::new(2)).unwrap();
+        assert!(big >= small + 1000);
+        assert_eq!(cache.entry_json_bytes(&SerCompound::new(3)), None);
+    }
+
+
+    #[test]
+    fn unchanged_overwrite_stays_clean() {
+        let (mut cache, fetcher) = counting_cache();
+        cache.get(SerCompound::new(962)).unwrap();
+        cache.mark_saved(None);
+        assert_eq!(cache.overwrite(SerCompound::new(962)).unwrap().unwrap().cid, 962);
+        assert!(!cache.is_dirty());
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 2);
+    }
+
+
+    #[test]
+    fn missing_and_null_fields_load_as_none() {
+        let json = json!({"version": 1, "cache": [{"namespace": "cid", "identifier": "962", "properties": {"cid": 962, "title": "water", "xlogp": null}}]});
+        let cache = CompoundCache::deserialize(json.to_string()).unwrap();
+        let water = cache.get_ref(&SerCompound::new(962)).unwrap();
+        assert_eq!(water.title.as_deref(), Some("water"));
+        assert_eq!((water.xlogp, water.atom_stereo_count, water.molecular_weight.as_deref()), (None, None, None));
+        let obj = json!({"charge": null, "complexity": 3}).as_object().unwrap().clone();
+        assert_eq!((get_opt_i32(&obj, "charge"), get_opt_i32(&obj, "complexity"), get_opt_i32(&obj, "tpsa")), (None, Some(3), None));
+    }
+
+
+    #[test]
+    fn finds_fluorine_compounds() {
+        let mut cache = CompoundCache::new();
+        for (cid, formula) in [(962, "H2O"), (6365, "CHF3"), (702, "C2H6O"), (1, "not a formula")] {
+            let compound = properties_from_object(json!({"cid": cid, "molecular_formula": formula}).as_object().unwrap()).unwrap();
+            cache.insert(SerCompound::new(cid as u32), compound).unwrap();
+        }
+        assert_eq!(cache.find_containing_element("F"), [&SerCompound::new(6365)]);
+        assert_eq!(cache.find_containing_element("C").len(), 2);
+        assert!(cache.find_containing_element("Fe").is_empty());
+    }
+
+
+    #[test]
+    fn key_diff_lists_both_sides() {
+        let mut a = CompoundCache::new();
+        let mut b = CompoundCache::new();
+        for cid in [1, 2, 3] {
+            a.insert(SerCompound::new(cid), props(cid as i32)).unwrap();
+        }
+        for cid in [3, 4] {
+            b.insert(SerCompound::new(cid), props(cid as i32)).unwrap();
+        }
+        let (only_a, only_b) = a.key_diff(&b);
+        assert_eq!(only_a, [&SerCompound::new(1), &SerCompound::new(2)]);
+        assert_eq!(only_b, [&SerCompound::new(4)]);
+    }
+
+
+    #[test]
+    fn derived_serde_matches_fixture() {
+        let mut properties: Map<String, Value> = ALL_PROPERTIES.iter().map(|property| (property_key(property).to_owned(), Value::Null)).collect();
+        properties.insert("cid".to_owned(), 962.into());
+        properties.insert("title".to_owned(), "water".into());
+        properties.insert("xlogp".to_owned(), json!(-0.5));
+        let fixture = json!({"version": 1, "cache": [{"namespace": "name", "identifier": "water", "properties": properties}]});
+
+        let cache: CompoundCache = serde_json::from_value(fixture.clone()).unwrap();
+        let output = serde_json::to_value(&cache).unwrap();
+        assert_eq!(output["version"], fixture["version"]);
+        for field in ["namespace", "identifier", "properties"] {
+            assert_eq!(output["cache"][0][field], fixture["cache"][0][field]);
+        }
+        assert_eq!(serde_json::to_value(SerCompound::new(962)).unwrap(), json!({"namespace": "cid", "identifier": "962"}));
+    }
+
+
+    #[test]
+    fn namespace_roundtrips_every_variant() {
+        for ns in Namespace::ALL {
+            assert_eq!(ns.to_string().parse::<Namespace>().unwrap(), *ns);
+            assert_eq!(ns.as_str().to_uppercase().parse::<Namespace>().unwrap(), *ns);
+            assert_eq!(serde_json::to_value(ns).unwrap(), ns.as_str());
+        }
+        assert!(matches!("element".parse::<Namespace>(), Err(CacheError::UnknownNamespace(ns)) if ns == "element"));
+    }
+
+
+    #[test]
+    fn display_name_prefers_title_then_iupac_name() {
+        let mut cache = CompoundCache::new();
+        cache.insert(SerCompound::new(962), props(962)).unwrap();
+        let unnamed = properties_from_object(json!({"cid": 702, "iupac_name": "ethanol"}).as_object().unwrap()).unwrap();
+        cache.insert(SerCompound::with_smiles("CCO"), unnamed).unwrap();
+        assert_eq!(cache.display_name(&SerCompound::new(962)).as_deref(), Some("water"));
+        assert_eq!(cache.display_name(&SerCompound::with_smiles("CCO")).as_deref(), Some("ethanol"));
+        cache.insert(SerCompound::with_smiles("C"), properties_from_object(json!({"cid": 297}).as_object().unwrap()).unwrap()).unwrap();
+        assert_eq!(cache.display_name(&SerCompound::with_smiles("C")).as_deref(), Some("C"));
+        assert_eq!(cache.display_name(&SerCompound::new(1)), None);
+    }
+
+
+    #[test]
+    fn remove_evicts_entry_and_aliases() {
+        let (mut cache, fetcher) = counting_cache();
+        cache.get(SerCompound::with_name("water")).unwrap();
+        assert_eq!(cache.remove(&SerCompound::new(962)).unwrap().cid, 962);
+        assert!(cache.get_ref(&SerCompound::with_name("water")).is_none());
+        assert_eq!(cache.alias_count(), 0);
+        assert!(cache.remove(&SerCompound::new(962)).is_none());
+        // The next `get` fetches again
+        cache.get(SerCompound::with_name("water")).unwrap();
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 2);
+    }
+
+
+    #[test]
+    fn markdown_report_has_header_and_rows() {
+        let mut cache = CompoundCache::new();
+        cache.insert(SerCompound::new(962), props(962)).unwrap();
+        cache.insert(SerCompound::new(702), props(702)).unwrap();
+        let mut out = Vec::new();
+        cache.to_markdown(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "| Name | Formula | Molecular weight | XLogP |");
+        assert_eq!(lines[1], "| --- | --- | --- | --- |");
+        assert_eq!(lines.len(), 4);
+        assert!(lines[2].starts_with("| water |"));
+    }
+
+
+    #[test]
+    fn counts_follow_inserts_and_removes() {
+        let mut cache = CompoundCache::new();
+        assert!(cache.is_empty());
+        for cid in [1, 2, 3] {
+            cache.insert(SerCompound::new(cid), props(cid as i32)).unwrap();
+        }
+        assert_eq!(cache.len(), 3);
+        cache.remove(&SerCompound::new(2));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains(&SerCompound::new(1)) && !cache.contains(&SerCompound::new(2)));
+        assert!(!cache.is_empty());
+    }
+
+
+    #[test]
+    fn inchi_prefix_is_checked() {
+        let standard = "InChI=1S/H2O/h1H2";
+        assert_eq!(SerCompound::try_with_inchi(standard).unwrap(), SerCompound::with_inchi(standard));
+        assert_eq!(InchiKind::of(standard), Some(InchiKind::Standard));
+        assert_eq!(InchiKind::of("InChI=1/H2O/h1H2"), Some(InchiKind::NonStandard));
+        assert!(matches!(SerCompound::try_with_inchi("foo/bar"), Err(CacheError::InvalidIdentifier(_))));
+        assert_eq!(InchiKind::of("foo/bar"), None);
+    }
+
+
+    #[test]
+    fn iterates_every_inserted_key() {
+        let mut cache = CompoundCache::new();
+        let inserted: HashSet<SerCompound> = [SerCompound::new(1), SerCompound::new(2), SerCompound::with_name("water")].into();
+        for cmp in &inserted {
+            cache.insert(cmp.clone(), props(1)).unwrap();
+        }
+        let mut seen = HashSet::new();
+        for (key, props) in &cache {
+            assert_eq!(props.cid, 1);
+            seen.insert(key.clone());
+        }
+        assert_eq!(seen, inserted);
+        assert_eq!(cache.iter().count(), 3);
+    }
+
+
+    #[test]
+    fn property_keys_are_renamed_on_request() {
+        let mut cache = CompoundCache::new();
+        cache.insert(SerCompound::new(962), props(962)).unwrap();
+        let renamed = cache.serialize_with_aliases(&HashMap::from([("molecular_weight", "mw")])).unwrap();
+        let props = renamed["cache"][0]["properties"].as_object().unwrap();
+        assert!(props.contains_key("mw") && !props.contains_key("molecular_weight"));
+        assert_eq!(props["title"], "water");
+        assert_eq!(props.len(), ALL_PROPERTIES.len() + 1);
+        assert!(cache.serialize().unwrap()["cache"][0]["properties"].get("molecular_weight").is_some());
+    }
+
+
+    #[test]
+    fn missing_file_loads_empty_and_saves_roundtrip() {
+        let dir = temp_dir("paths");
+        let path = dir.join("compounds.json");
+        let cache = CompoundCache::load_from_path(&path).unwrap();
+        assert!(cache.is_empty());
+        assert!(!path.exists());
+
+        let mut cache = cache;
+        cache.insert(SerCompound::new(962), props(962)).unwrap();
+        cache.save_to_path(&path).unwrap();
+        assert_eq!(CompoundCache::load_from_path(&path).unwrap().get_ref(&SerCompound::new(962)).unwrap().cid, 962);
+        // Only the cache file is left behind, no temporary one
+        assert_eq!(fs::read_dir(&dir).unwrap().filter(|entry| !entry.as_ref().unwrap().path().ends_with("compounds.json.lock")).count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn sparse_responses_are_degraded() {
+        let (cache, _) = counting_cache();
+        let mut strict = cache.with_min_properties(10).with_strict_properties();
+        assert!(matches!(strict.get(SerCompound::new(962)), Err(CacheError::DegradedResponse(2, n)) if n == ALL_PROPERTIES.len()));
+        assert!(strict.is_empty());
+
+        let (cache, _) = counting_cache();
+        let mut lenient = cache.with_min_properties(10);
+        lenient.get(SerCompound::new(962)).unwrap();
+        assert_eq!(lenient.degraded().collect::<Vec<_>>(), [&SerCompound::new(962)]);
+    }
+
+
+    #[test]
+    fn get_with_fetches_only_the_subset() {
+        /// Answers with water, leaving out what wasn't asked for, like PubChem.
+        struct SubsetFetcher(CountingFetcher);
+        impl PropertyFetcher for SubsetFetcher {
+            fn fetch(&self, _: &SerCompound, properties: &[CompoundProperty]) -> Result<FetchedProperties, CacheError> {
+                self.0.calls.fetch_add(1, Ordering::SeqCst);
+                let mut water = properties_from_object(json!({"cid": 962, "molecular_formula": "H2O", "molecular_weight": "18.015", "title": "water"}).as_object().unwrap())?;
+                for property in ALL_PROPERTIES.iter().filter(|property| !properties.contains(property)) {
+                    clear_property(&mut water, property);
+                }
+                Ok(water.into())
+            }
+        }
+        let fetcher = CountingFetcher::default();
+        let mut cache = CompoundCache::new().with_fetcher(SubsetFetcher(fetcher.clone()));
+        let water = cache.get_with(SerCompound::new(962), &[CompoundProperty::MolecularFormula]).unwrap();
+        assert_eq!(water.molecular_formula.as_deref(), Some("H2O"));
+        assert_eq!((water.molecular_weight.as_deref(), water.title.as_deref()), (None, None));
+        cache.get_with(SerCompound::new(962), &[CompoundProperty::MolecularFormula]).unwrap();
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 1);
+        // A full `get` knows the entry is partial
+        assert_eq!(cache.get(SerCompound::new(962)).unwrap().1.molecular_weight.as_deref(), Some("18.015"));
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 2);
+    }
+
+
+    #[cfg(feature = "ordered")]
+    #[test]
+    fn ordered_feature_keeps_insertion_order() {
+        let mut cache = CompoundCache::new();
+        let keys = [SerCompound::new(3), SerCompound::with_name("water"), SerCompound::new(1)];
+        for key in &keys {
+            cache.insert(key.clone(), props(1)).unwrap();
+        }
+        assert_eq!(cache.iter().map(|(key, _)| key).collect::<Vec<_>>(), keys.iter().collect::<Vec<_>>());
+        let json = cache.serialize().unwrap();
+        assert_eq!(json["cache"][1]["identifier"], "water");
+    }
+
+
+    #[test]
+    fn fetched_set_roundtrips() {
+        const LEAN: &[CompoundProperty] = &[CompoundProperty::Title, CompoundProperty::XLogP];
+        let (cache, _) = counting_cache();
+        let mut cache = cache.with_namespace_properties(Namespace::Cid, LEAN);
+        cache.get(SerCompound::new(962)).unwrap();
+        cache.insert(SerCompound::new(1), props(1)).unwrap();
+        let expected: HashSet<CompoundProperty> = LEAN.iter().copied().collect();
+        assert_eq!(cache.fetched_properties(&SerCompound::new(962)), Some(&expected));
+        let loaded = CompoundCache::deserialize(cache.serialize().unwrap().to_string()).unwrap();
+        assert_eq!(loaded.fetched_properties(&SerCompound::new(962)), Some(&expected));
+        // Inserted entries count what has a value
+        assert_eq!(loaded.fetched_properties(&SerCompound::new(1)), Some(&expected));
+        assert_eq!(loaded.fetched_properties(&SerCompound::new(2)), None);
+    }
+
+
+    #[test]
+    fn counts_fetches_per_namespace() {
+        let (mut cache, _) = counting_cache();
+        cache.get(SerCompound::new(1)).unwrap();
+        cache.get(SerCompound::new(2)).unwrap();
+        cache.get(SerCompound::with_name("water")).unwrap();
+        cache.get(SerCompound::new(1)).unwrap();
+        assert_eq!(cache.fetches_by_namespace(), HashMap::from([("cid".to_owned(), 2), ("name".to_owned(), 1)]));
+    }
+
+
+    #[test]
+    fn clear_aliases_keeps_primaries() {
+        let (mut cache, _) = counting_cache();
+        cache.get(SerCompound::new(962)).unwrap();
+        cache.get(SerCompound::with_smiles("O")).unwrap();
+        cache.get(SerCompound::with_name("water")).unwrap();
+        cache.deduplicate();
+        assert_eq!((cache.len(), cache.alias_count()), (1, 2));
+        cache.clear_aliases();
+        assert_eq!((cache.len(), cache.alias_count()), (1, 0));
+        assert!(cache.get_ref(&SerCompound::new(962)).is_some());
+        assert!(cache.get_ref(&SerCompound::with_name("water")).is_none());
+    }
+
+
+    #[test]
+    fn expired_entries_are_refetched() {
+        let (cache, fetcher) = counting_cache();
+        let mut cache = cache.with_ttl(Duration::from_secs(60));
+        cache.get(SerCompound::new(962)).unwrap();
+        let json = cache.serialize().unwrap();
+        assert!(json["cache"][0]["fetched_at"].is_u64());
+
+        cache.cache.get_mut(&SerCompound::new(962)).unwrap().fetched_at = SystemTime::now() - Duration::from_secs(120);
+        assert!(cache.get_noreq_fresh(&SerCompound::new(962)).is_none());
+        assert!(cache.get_ref(&SerCompound::new(962)).is_some());
+        assert!(cache.get(SerCompound::new(962)).unwrap().0);
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 2);
+        assert!(cache.get_noreq_fresh(&SerCompound::new(962)).is_some());
+    }
+
+
+    #[test]
+    fn caches_from_sdf_smiles_tag() {
+        let dir = temp_dir("sdf");
+        let (sdf, empty) = (dir.join("water.sdf"), dir.join("empty.sdf"));
+        fs::write(&sdf, "water\n  example\n\n  1  0  0  0  0  0  0  0  0  0999 V2000\n    0.0000    0.0000    0.0000 O   0  0\nM  END\n> <PUBCHEM_SMILES>\nO\n\n$$$$\n").unwrap();
+        fs::write(&empty, "water\nM  END\n$$$$\n").unwrap();
+        let (mut cache, fetcher) = counting_cache();
+        assert_eq!(cache.cache_from_molfile(&sdf).unwrap().cid, 962);
+        assert!(cache.contains(&SerCompound::with_smiles("O")));
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 1);
+        assert!(matches!(cache.cache_from_molfile(&empty), Err(CacheError::NoIdentifier(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[cfg(feature = "fs-lock")]
+    #[test]
+    fn save_waits_for_the_file_lock() {
+        let dir = temp_dir("lock");
+        let path = dir.join("compounds.json");
+        let mut cache = CompoundCache::new();
+        cache.insert(SerCompound::new(962), props(962)).unwrap();
+
+        let lock = lock_file(&path, true).unwrap();
+        let saver = {
+            let path = path.clone();
+            thread::spawn(move || cache.save_to_path(path))
+        };
+        thread::sleep(Duration::from_millis(100));
+        assert!(!saver.is_finished());
+        assert!(!path.exists());
+        drop(lock);
+        saver.join().unwrap().unwrap();
+        assert_eq!(CompoundCache::load_from_path(&path).unwrap().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn retain_filters_on_a_property_and_clear_empties() {
+        let mut cache = CompoundCache::new();
+        for (cid, heavy_atoms) in [(962, 1), (2244, 13), (702, 3)] {
+            let compound = properties_from_object(json!({"cid": cid, "heavy_atom_count": heavy_atoms}).as_object().unwrap()).unwrap();
+            cache.insert(SerCompound::new(cid as u32), compound).unwrap();
+        }
+        cache.retain(|_, props| props.heavy_atom_count.is_some_and(|n| n <= 5));
+        assert_eq!(cache.len(), 2);
+        assert!(!cache.contains(&SerCompound::new(2244)));
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+
+
+    #[test]
+    fn summary_counts_entries_by_namespace() {
+        let (mut cache, _) = counting_cache();
+        cache.get(SerCompound::new(1)).unwrap();
+        cache.get(SerCompound::new(2)).unwrap();
+        cache.get(SerCompound::with_name("water")).unwrap();
+        let summary = cache.summarize();
+        assert_eq!((summary.entries, summary.aliases), (3, 1));
+        assert_eq!(summary.by_namespace, BTreeMap::from([(Namespace::Cid, 2), (Namespace::Name, 1)]));
+        assert!(summary.completeness > 0.0 && summary.completeness < 1.0);
+        assert_eq!(summary.estimated_bytes, cache.estimated_json_bytes());
+        assert_eq!(CompoundCache::new().summarize().completeness, 0.0);
+    }
+
+
+    #[test]
+    fn collects_into_a_cache() {
+        let cache: CompoundCache = [(SerCompound::new(1), props(1)), (SerCompound::new(2), props(2))].into_iter().collect();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get_ref(&SerCompound::new(2)).unwrap().cid, 2);
+        assert!(CompoundCache::<SerCompound>::default().is_empty());
+    }
+
+
+    #[test]
+    fn finds_name_keyed_entry_by_cid() {
+        let mut cache = CompoundCache::new();
+        cache.insert(SerCompound::with_name("water"), props(962)).unwrap();
+        let (key, found) = cache.find_by_cid(962).unwrap();
+        assert_eq!((key, found.cid), (&SerCompound::with_name("water"), 962));
+        assert!(cache.find_by_cid(1).is_none());
+    }
+
+
+    #[test]
+    fn deduplicate_keeps_the_cid_key() {
+        let mut cache = CompoundCache::new();
+        for key in [SerCompound::with_smiles("O"), SerCompound::with_name("water"), SerCompound::new(962), SerCompound::new(1)] {
+            let cid = if key == SerCompound::new(1) { 1 } else { 962 };
+            cache.insert(key, props(cid)).unwrap();
+        }
+        assert_eq!(cache.deduplicate(), 2);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.cache.contains_key(&SerCompound::new(962)));
+        assert_eq!(cache.get_ref(&SerCompound::with_name("water")).unwrap().cid, 962);
+        assert_eq!(cache.deduplicate(), 0);
+    }
+
+
+    #[test]
+    fn csv_export_matches_fixture() {
+        let mut cache = CompoundCache::new();
+        cache.insert(SerCompound::new(962), props(962)).unwrap();
+        let inchi = properties_from_object(json!({"cid": 702, "inchi": "InChI=1S/C2H6O/c1-2-3/h3H,2H2,1H3"}).as_object().unwrap()).unwrap();
+        cache.insert(SerCompound::with_name("ethanol"), inchi).unwrap();
+        let mut out = Vec::new();
+        cache.to_csv(&mut out).unwrap();
+
+        let row = |prefix: &str, cell: &dyn Fn(&CompoundProperty) -> &'static str| -> String {
+            prefix.to_owned() + &ALL_PROPERTIES.iter().map(|property| format!(",{}", cell(property))).collect::<String>()
+        };
+        let header = row("namespace,identifier,cid", &|property| property_key(property));
+        let water = row("cid,962,962", &|property| match property {
+            CompoundProperty::Title => "water",
+            CompoundProperty::XLogP => "-0.5",
+            _ => ""
+        });
+        let ethanol = row("name,ethanol,702", &|property| match property {
+            CompoundProperty::InChI => "\"InChI=1S/C2H6O/c1-2-3/h3H,2H2,1H3\"",
+            _ => ""
+        });
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{header}\n{water}\n{ethanol}\n"));
+    }
+
+
+    #[test]
+    fn format_entry_renders_known_block() {
+        let mut cache = CompoundCache::new();
+        let water = properties_from_object(json!({"cid": 962, "title": "water", "molecular_formula": "H2O", "molecular_weight": "18.015", "xlogp": -0.5}).as_object().unwrap()).unwrap();
+        cache.insert(SerCompound::new(962), water).unwrap();
+        let expected = "\
+Title:            water
+Formula:          H2O
+Molecular weight: 18.015
+Canonical SMILES: -
+InChI:            -
+InChIKey:         -
+XLogP:            -0.5
+";
+        assert_eq!(cache.format_entry(&SerCompound::new(962)).unwrap(), expected);
+        assert_eq!(cache.format_entry(&SerCompound::new(1)), None);
+    }
+
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_is_smaller_than_json() {
+        let (mut cache, _) = counting_cache();
+        for cid in 1..=20 {
+            cache.get(SerCompound::new(cid)).unwrap();
+        }
+        let binary = cache.serialize_binary().unwrap();
+        assert!(binary.len() < cache.serialize().unwrap().to_string().len());
+        let loaded = CompoundCache::deserialize_binary(&binary).unwrap();
+        assert_eq!(loaded.len(), 20);
+        assert_eq!(loaded.get_ref(&SerCompound::new(7)).unwrap().xlogp, Some(-0.5));
+    }
+
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_roundtrip_matches_plain() {
+        let dir = temp_dir("gzip");
+        let (plain, gz) = (dir.join("compounds.json"), dir.join("compounds.json.gz"));
+        let (mut cache, _) = counting_cache();
+        cache.get(SerCompound::with_name("water")).unwrap();
+        cache.get(SerCompound::new(1)).unwrap();
+        cache.save_to_path(&plain).unwrap();
+        cache.save_to_path(&gz).unwrap();
+        // The gzip magic number
+        assert_eq!(fs::read(&gz).unwrap()[..2], [0x1f, 0x8b]);
+        let from_plain = CompoundCache::load_from_path(&plain).unwrap();
+        let from_gz = CompoundCache::load_from_path(&gz).unwrap();
+        assert_eq!(from_gz.to_identity_map(), from_plain.to_identity_map());
+        assert_eq!(from_gz.get_ref(&SerCompound::new(1)).unwrap().title, from_plain.get_ref(&SerCompound::new(1)).unwrap().title);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+
+    #[test]
+    fn concurrent_hits_only_take_the_read_lock() {
+        let (mut cache, fetcher) = counting_cache();
+        cache.get(SerCompound::new(962)).unwrap();
+        let shared = SharedCompoundCache::new(cache);
+        // Hits would deadlock if they needed the write lock while this is held
+        let reader = shared.read();
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| assert_eq!(shared.get(SerCompound::new(962)).unwrap().cid, 962));
+            }
+        });
+        drop(reader);
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(shared.read().stats().hits, 8);
+    }
+
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn get_async_hit_makes_no_request() {
+        let (mut cache, fetcher) = counting_cache();
+        assert!(!cache.get_async(SerCompound::new(962)).await.unwrap().0);
+        let (cached, props) = cache.get_async(SerCompound::new(962)).await.unwrap();
+        assert!(cached);
+        assert_eq!(props.cid, 962);
+        assert_eq!(fetcher.calls.load(Ordering::SeqCst), 1);
+    }
+
+
+    #[test]
+    fn rate_limiter_spaces_requests() {
+        let mut limiter = RateLimiter::new(5);
+        let start = Instant::now();
+        assert_eq!(limiter.take(start), Duration::ZERO);
+        assert!((limiter.take(start).as_secs_f64() - 0.2).abs() < 1e-9);
+        assert!((limiter.take(start).as_secs_f64() - 0.4).abs() < 1e-9);
+        // An idle limiter lets one request through right away, but doesn't save up a burst
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.take(later), Duration::ZERO);
+        assert!((limiter.take(later).as_secs_f64() - 0.2).abs() < 1e-9);
+        assert!((limiter.take(later + Duration::from_millis(100)).as_secs_f64() - 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn estimate_counts_rate_limit() {
+        let cache = CompoundCache::new().with_rate_limit(5);
+        assert_eq!(cache.estimate_fetch_time(0), Duration::ZERO);
+        assert_eq!(cache.estimate_fetch_time(3), Duration::from_millis(400));
+    }
+
+
+    #[test]
+    fn checks_smiles_syntax() {
+        for smiles in ["c1ccccc1", "C[C@H](N)C(=O)O", "[Na+].[Cl-]", "O"] {
+            assert!(SerCompound::with_smiles(smiles).to_compound().is_ok(), "{smiles}");
+        }
+        for smiles in ["C1CC", "C(C", "Oxx", "C[Na"] {
+            assert!(matches!(SerCompound::with_smiles(smiles).to_compound(), Err(CacheError::InvalidSmiles(_))), "{smiles}");
+        }
+    }
+
+
+    #[test]
+    fn checks_inchikey_syntax() {
+        assert!(SerCompound::with_inchikey("XLYOFNOQVPJJNP-UHFFFAOYSA-N").to_compound().is_ok());
+        // Too short, lowercase, and missing a dash
+        for key in ["water", "XLYOFNOQVPJJNP-UHFFFAOYSA", "xlyofnoqvpjjnp-uhfffaoysa-n", "XLYOFNOQVPJJNPUHFFFAOYSA-N"] {
+            assert!(matches!(SerCompound::with_inchikey(key).to_compound(), Err(CacheError::InvalidInchiKey(_))), "{key}");
+        }
+        assert!(matches!(SerCompound::with_nmsp_iden(Namespace::Cid, "-1").to_compound(), Err(CacheError::InvalidCid(_))));
+    }
+
+
+    #[test]
+    fn merge_normalizes_and_filters() {
+        let mut cache = CompoundCache::new()
+            .with_allowed_properties(&[CompoundProperty::Title])
+            .with_key_normalizer(|cmp: SerCompound| SerCompound { identifier: cmp.identifier.to_lowercase(), ..cmp });
+        let mut other = CompoundCache::new();
+        other.insert(SerCompound::with_name("Water"), props(962)).unwrap();
+        other.aliases.insert(SerCompound::new(1), SerCompound::with_name("missing"));
+        let summary = cache.merge(other, MergeStrategy::KeepExisting);
+        assert_eq!(summary, MergeSummary { added: 1, overwritten: 0, skipped: 0 });
+        let props = cache.get_ref(&SerCompound::with_name("water")).unwrap();
+        assert_eq!(props.title.as_deref(), Some("water"));
+        assert_eq!(props.xlogp, None);
+        assert_eq!(cache.alias_count(), 0);
+    }
+
+    #[test]
+    fn merge_strategies_on_overlapping_keys() {
+        let build = |entries: &[(u32, f64)]| -> CompoundCache {
+            entries.iter().map(|&(cid, xlogp)| {
+                let mut compound = props(cid as i32);
+                compound.xlogp = Some(xlogp);
+                (SerCompound::new(cid), compound)
+            }).collect()
+        };
+        let mut keep = build(&[(1, 1.0), (2, 1.0)]);
+        let summary = keep.merge(build(&[(2, 2.0), (3, 2.0)]), MergeStrategy::KeepExisting);
+        assert_eq!(summary, MergeSummary { added: 1, overwritten: 0, skipped: 1 });
+        assert_eq!(keep.get_ref(&SerCompound::new(2)).unwrap().xlogp, Some(1.0));
+
+        let mut overwrite = build(&[(1, 1.0), (2, 1.0)]);
+        let summary = overwrite.merge(build(&[(2, 2.0), (3, 2.0)]), MergeStrategy::Overwrite);
+        assert_eq!(summary, MergeSummary { added: 1, overwritten: 1, skipped: 0 });
+        assert_eq!(overwrite.get_ref(&SerCompound::new(2)).unwrap().xlogp, Some(2.0));
+        assert_eq!(overwrite.len(), 3);
+    }
+
+
+    #[test]
+    fn stats_follow_misses_hits_and_refetches() {
+        let (cache, _) = counting_cache();
+        let mut cache = cache.with_ttl(Duration::from_secs(60));
+        cache.get(SerCompound::new(962)).unwrap();
+        assert_eq!(cache.stats(), CacheStats { hits: 0, misses: 1, network_fetches: 1 });
+        cache.get(SerCompound::new(962)).unwrap();
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1, network_fetches: 1 });
+        cache.cache.get_mut(&SerCompound::new(962)).unwrap().fetched_at = SystemTime::now() - Duration::from_secs(120);
+        cache.get(SerCompound::new(962)).unwrap();
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 2, network_fetches: 2 });
+        cache.reset_stats();
+        assert_eq!(cache.stats(), CacheStats::default());
+    }
+
+
+    #[test]
+    fn from_compound_reproduces_the_compound() {
+        let cmp = SerCompound::from_compound(Namespace::Name, "water");
+        assert_eq!(cmp, SerCompound::with_name("water"));
+        // `Compound` has no `PartialEq`, so compare what it prints
+        assert_eq!(format!("{:?}", cmp.to_compound().unwrap()), format!("{:?}", Compound::with_name("water")));
+        assert_eq!(format!("{:?}", SerCompound::from_compound(Namespace::Cid, "962").to_compound().unwrap()), format!("{:?}", Compound::new(962)));
+    }
+
+
+    #[test]
+    fn display_and_from_str_roundtrip() {
+        for cmp in [SerCompound::new(962), SerCompound::with_name("water"), SerCompound::with_smiles("C(=O)O"), SerCompound::with_inchi("InChI=1S/H2O/h1H2"), SerCompound::with_inchikey("XLYOFNOQVPJJNP-UHFFFAOYSA-N")] {
+            assert_eq!(cmp.to_string().parse::<SerCompound>().unwrap(), cmp);
+        }
+        assert_eq!("CID: 962 ".parse::<SerCompound>().unwrap(), SerCompound::new(962));
+        for bad in ["962", "name:", "name:wa\0ter", "inchi:water", "inchi:InChI=1S/", "element:O"] {
+            assert!(bad.parse::<SerCompound>().is_err(), "{bad}");
+        }
+    }
+
+
+    #[test]
+    fn filters_on_xlogp() {
+        let mut cache = CompoundCache::new();
+        for (cid, xlogp) in [(962, -0.5), (2244, 1.2), (702, -0.1)] {
+            let mut compound = props(cid);
+            compound.xlogp = Some(xlogp);
+            cache.insert(SerCompound::new(cid as u32), compound).unwrap();
+        }
+        let mut hydrophilic: Vec<i32> = cache.filter(|props| props.xlogp.is_some_and(|xlogp| xlogp < 0.0)).into_iter().map(|(_, props)| props.cid).collect();
+        hydrophilic.sort();
+        assert_eq!(hydrophilic, [702, 962]);
+    }
+}
@@ -0,0 +1,39 @@
+//! Token bucket used to keep fetches under PubChem's request rate. It holds a single token, so requests are spaced evenly instead of bursting.
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimiter {
+    per_second: u32,
+    /// At most 1, when a request may go out right away. Negative when callers are already waiting for tokens.
+    tokens: f64,
+    last: Option<Instant>
+}
+
+impl RateLimiter {
+    /// Allows `per_second` requests per second, at least one.
+    pub(crate) fn new(per_second: u32) -> RateLimiter {
+        RateLimiter { per_second: per_second.max(1), tokens: 1.0, last: None }
+    }
+    pub(crate) fn per_second(&self) -> u32 {
+        self.per_second
+    }
+    /// The spacing between requests.
+    pub(crate) fn interval(&self) -> Duration {
+        Duration::from_secs(1) / self.per_second
+    }
+    /// Takes the token for a request made at `now`, returning how long to wait before sending it. Zero if the last request went out at least `interval` ago.
+    pub(crate) fn take(&mut self, now: Instant) -> Duration {
+        let rate = self.per_second as f64;
+        if let Some(last) = self.last {
+            self.tokens = (self.tokens + now.saturating_duration_since(last).as_secs_f64() * rate).min(1.0);
+        }
+        self.last = Some(now);
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
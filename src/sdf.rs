@@ -0,0 +1,38 @@
+//! Reading identifiers out of the data items of a MOL/SDF record (a `> <TAG>` line followed by the value). The connection table itself is not parsed.
+
+use crate::Namespace;
+
+/// The data items of the first record, as `(tag, value)` pairs. Values spanning several lines are joined without a separator.
+pub(crate) fn data_items(text: &str) -> Vec<(String, String)> {
+    let mut items = Vec::new();
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        if line.starts_with("$$$$") {
+            break;
+        }
+        let Some(header) = line.strip_prefix('>') else {
+            continue;
+        };
+        let Some(tag) = header.split_once('<').and_then(|(_, rest)| rest.split_once('>')).map(|(tag, _)| tag) else {
+            continue;
+        };
+        let value: String = lines.by_ref().take_while(|line| !line.trim().is_empty()).map(str::trim).collect();
+        items.push((tag.to_owned(), value));
+    }
+    items
+}
+
+/// An identifier to look the record up by: the first InChI data item, otherwise the first SMILES one.
+pub(crate) fn structure_identifier(text: &str) -> Option<(Namespace, String)> {
+    let items = data_items(text);
+    let inchi = items.iter().find(|(tag, value)| {
+        let tag = tag.to_ascii_uppercase();
+        tag.contains("INCHI") && !tag.contains("INCHIKEY") && value.starts_with("InChI=")
+    });
+    if let Some((_, inchi)) = inchi {
+        return Some((Namespace::InChI, inchi.clone()));
+    }
+    items.into_iter()
+        .find(|(tag, value)| tag.to_ascii_uppercase().contains("SMILES") && !value.is_empty())
+        .map(|(_, smiles)| (Namespace::Smiles, smiles))
+}
@@ -0,0 +1,78 @@
+use std::{env, sync::{OnceLock, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard}};
+
+use pubchem::model::rest::Properties;
+
+use crate::{propclone, CacheError, CompoundCache, SerCompound};
+
+/// Environment variable naming the file `global` loads its cache from.
+pub const GLOBAL_CACHE_PATH_VAR: &str = "CHEM_CACHE_PATH";
+
+/// A `CompoundCache` behind a lock, for sharing between threads. Lookups return copies of the properties, as references can't outlive the lock.
+#[derive(Debug)]
+pub struct SharedCompoundCache(RwLock<CompoundCache>);
+
+impl SharedCompoundCache {
+    pub fn new(cache: CompoundCache) -> SharedCompoundCache {
+        SharedCompoundCache(RwLock::new(cache))
+    }
+    /// Locks the cache for reading. A panic in another thread while it held the lock is ignored.
+    pub fn read(&self) -> RwLockReadGuard<'_, CompoundCache> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+    /// Locks the cache for writing. A panic in another thread while it held the lock is ignored.
+    pub fn write(&self) -> RwLockWriteGuard<'_, CompoundCache> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+    /// The cached properties, without fetching (see `CompoundCache::get_ref`).
+    pub fn get_ref(&self, cmp: &SerCompound) -> Option<Properties> {
+        self.read().get_ref(cmp).map(propclone)
+    }
+    /// The properties of `cmp`, fetching and storing them on a miss. A hit only takes the read lock, so concurrent hits don't wait for each other. Expired and partial entries count as misses, as with `CompoundCache::store`.
+    pub fn get(&self, cmp: SerCompound) -> Result<Properties, CacheError> {
+        {
+            let cache = self.read();
+            let hit = cache.get_hit(&cmp).map(propclone);
+            cache.record_lookup(hit.is_some());
+            if let Some(props) = hit {
+                return Ok(props);
+            }
+        }
+        let mut cache = self.write();
+        cache.store(cmp.clone())?;
+        cache.get_ref(&cmp).map(propclone).ok_or(CacheError::NotCached(cmp))
+    }
+    /// See `CompoundCache::store`.
+    pub fn store(&self, cmp: SerCompound) -> Result<bool, CacheError> {
+        self.write().store(cmp)
+    }
+}
+
+impl From<CompoundCache> for SharedCompoundCache {
+    fn from(cache: CompoundCache) -> Self {
+        SharedCompoundCache::new(cache)
+    }
+}
+
+/// A process-wide cache, created on first use. If `CHEM_CACHE_PATH` names an existing file, the cache is loaded from it; otherwise it starts empty. It is never saved automatically.
+///
+/// If the file exists but can't be loaded, the cache starts empty too, and `global_load_error` tells why.
+pub fn global() -> &'static SharedCompoundCache {
+    &init_global().0
+}
+
+/// Why `global` couldn't load the file named by `CHEM_CACHE_PATH` and started empty, if it couldn't. Creates the global cache if it doesn't exist yet.
+pub fn global_load_error() -> Option<&'static str> {
+    init_global().1.as_deref()
+}
+
+fn init_global() -> &'static (SharedCompoundCache, Option<String>) {
+    static GLOBAL: OnceLock<(SharedCompoundCache, Option<String>)> = OnceLock::new();
+    GLOBAL.get_or_init(|| {
+        let (cache, error) = match env::var_os(GLOBAL_CACHE_PATH_VAR).map(CompoundCache::load_from_path) {
+            Some(Ok(cache)) => (cache, None),
+            Some(Err(e)) => (CompoundCache::new(), Some(e.to_string())),
+            None => (CompoundCache::new(), None)
+        };
+        (SharedCompoundCache::new(cache), error)
+    })
+}
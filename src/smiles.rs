@@ -0,0 +1,112 @@
+//! Syntax check for SMILES strings, catching typos before they cost a PubChem request. It doesn't parse the structure (valences, aromaticity and stereochemistry are PubChem's job).
+
+const ELEMENTS: [&str; 118] = [
+    "H", "He", "Li", "Be", "B", "C", "N", "O", "F", "Ne", "Na", "Mg", "Al", "Si", "P", "S", "Cl", "Ar", "K", "Ca",
+    "Sc", "Ti", "V", "Cr", "Mn", "Fe", "Co", "Ni", "Cu", "Zn", "Ga", "Ge", "As", "Se", "Br", "Kr", "Rb", "Sr", "Y", "Zr",
+    "Nb", "Mo", "Tc", "Ru", "Rh", "Pd", "Ag", "Cd", "In", "Sn", "Sb", "Te", "I", "Xe", "Cs", "Ba", "La", "Ce", "Pr", "Nd",
+    "Pm", "Sm", "Eu", "Gd", "Tb", "Dy", "Ho", "Er", "Tm", "Yb", "Lu", "Hf", "Ta", "W", "Re", "Os", "Ir", "Pt", "Au", "Hg",
+    "Tl", "Pb", "Bi", "Po", "At", "Rn", "Fr", "Ra", "Ac", "Th", "Pa", "U", "Np", "Pu", "Am", "Cm", "Bk", "Cf", "Es", "Fm",
+    "Md", "No", "Lr", "Rf", "Db", "Sg", "Bh", "Hs", "Mt", "Ds", "Rg", "Cn", "Nh", "Fl", "Mc", "Lv", "Ts", "Og"
+];
+/// Atoms that may be written without brackets.
+const ORGANIC: [&str; 16] = ["Cl", "Br", "B", "C", "N", "O", "P", "S", "F", "I", "b", "c", "n", "o", "p", "s"];
+/// Aromatic atoms that may appear in brackets.
+const AROMATIC: [&str; 8] = ["se", "as", "te", "b", "c", "n", "o", "p"];
+const BONDS: &str = "-=#$:/\\";
+
+/// What the last token was, deciding what may follow it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Previous {
+    Start,
+    /// An atom, ring closure or closed branch, which bonds and branches can attach to.
+    Atom,
+    /// An opened branch.
+    Branch,
+    Bond,
+    /// A `.` between disconnected parts.
+    Dot
+}
+
+/// Whether `smiles` is well-formed: only known atoms, bonds and ring closures, with balanced brackets, parentheses and ring closures.
+pub(crate) fn is_valid_smiles(smiles: &str) -> bool {
+    let mut rest = smiles;
+    let mut previous = Previous::Start;
+    let mut depth = 0usize;
+    let mut open_rings = Vec::new();
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '[' => {
+                let Some(end) = rest.find(']') else {
+                    return false;
+                };
+                if !is_valid_bracket_atom(&rest[1..end]) {
+                    return false;
+                }
+                previous = Previous::Atom;
+                end + 1
+            }
+            '*' => {
+                previous = Previous::Atom;
+                1
+            }
+            '(' if previous == Previous::Atom => {
+                depth += 1;
+                previous = Previous::Branch;
+                1
+            }
+            ')' if previous == Previous::Atom && depth > 0 => {
+                depth -= 1;
+                1
+            }
+            '0'..='9' | '%' if matches!(previous, Previous::Atom | Previous::Bond) => {
+                let len = if c == '%' { 3 } else { 1 };
+                let label = match rest.get(1..len) {
+                    Some(digits) if digits.bytes().all(|b| b.is_ascii_digit()) => &rest[..len],
+                    _ => return false
+                };
+                match open_rings.iter().position(|open| *open == label) {
+                    Some(i) => {
+                        open_rings.swap_remove(i);
+                    }
+                    None => open_rings.push(label)
+                }
+                previous = Previous::Atom;
+                len
+            }
+            '.' if previous == Previous::Atom => {
+                previous = Previous::Dot;
+                1
+            }
+            _ if BONDS.contains(c) && matches!(previous, Previous::Atom | Previous::Branch) => {
+                previous = Previous::Bond;
+                1
+            }
+            _ => match ORGANIC.iter().find(|atom| rest.starts_with(*atom)) {
+                Some(atom) => {
+                    previous = Previous::Atom;
+                    atom.len()
+                }
+                None => return false
+            }
+        };
+        rest = &rest[len..];
+    }
+    previous == Previous::Atom && depth == 0 && open_rings.is_empty()
+}
+
+/// Checks the inside of `[...]`: an optional isotope, the atom, then chirality, hydrogens, charge and atom class.
+fn is_valid_bracket_atom(atom: &str) -> bool {
+    let atom = atom.trim_start_matches(|c: char| c.is_ascii_digit());
+    let symbol = if atom.starts_with('*') {
+        Some("*")
+    } else {
+        ELEMENTS.iter().chain(&AROMATIC)
+            .filter(|symbol| atom.starts_with(*symbol))
+            .max_by_key(|symbol| symbol.len())
+            .copied()
+    };
+    match symbol {
+        Some(symbol) => atom[symbol.len()..].chars().all(|c| c.is_ascii_alphanumeric() || "@+-:".contains(c)),
+        None => false
+    }
+}